@@ -2,6 +2,7 @@ use log::Level;
 use std::f32::consts::PI;
 
 use super::errors::*;
+use crate::math_tools::*;
 use crate::objects_def::*;
 use crate::output::*;
 
@@ -10,107 +11,292 @@ enum Direction {
     CCW,
 }
 
+type UnitVector = (f32, f32, f32);
+
+// Radii and chords below this length (mm) are considered null
+const MIN_ARC_RADIUS: f32 = 1e-4;
+
+struct ArcGeometry {
+    cp: Coord,
+    cd: Coord,
+    helix: f32,
+    radius: f32,
+    theta: f32,
+    sign: f32,
+}
+
 impl<'a> ModalGroup<'a> {
-    pub fn get_stats(&'a self, warnlog: &mut Warnlog) -> Result<(f32, f32)> {
-        let dist = self
-            .get_distance()
-            .chain_err(|| "Error computing travel distance")?;
+    pub fn get_stats(&'a self, warnlog: &mut Warnlog) -> Result<Vec<Block>> {
+        match self.dest {
+            Some(ref dest) if !dest.is_empty() => {}
+            _ => return Ok(Vec::new()),
+        }
+        let move_type = match self.move_type {
+            Some(ref mt) => mt,
+            None => return Ok(Vec::new()),
+        };
+
+        let arc = match move_type {
+            &MoveTypes::G2 => self.get_arc_geometry(Direction::CW),
+            &MoveTypes::G3 => self.get_arc_geometry(Direction::CCW),
+            _ => Ok(None),
+        }
+        .chain_err(|| "Error computing arc geometry")?;
+        let is_arc = match move_type {
+            &MoveTypes::G2 | &MoveTypes::G3 => true,
+            _ => false,
+        };
+        if is_arc && arc.is_none() {
+            warnlog.warn(WarnType::DegenerateArc(self.line_number));
+        }
+
+        let (dist, entry_dir, exit_dir) = match arc {
+            Some(ref arc) => self.get_dist_arc(arc),
+            None => self.get_dist_line(),
+        }
+        .chain_err(|| "Error computing travel distance")?;
 
         if log_enabled!(Level::Debug) {
             debug!("Distance: {}", dist);
         }
 
-        let time = self
-            .get_duration(dist, warnlog)
-            .chain_err(|| "Error computing travel duration")?;
+        if dist == 0. {
+            return Ok(Vec::new());
+        }
+
+        let deltas = self.get_deltas().chain_err(|| "Error computing deltas")?;
+        let extra = self.get_extra_deltas();
+        self.check_travel(deltas, warnlog);
+
+        match move_type {
+            &MoveTypes::G0 => {}
+            _ if self.spindle.dir == SpindleDir::Off => {
+                warnlog.warn(WarnType::SpindleOff(self.line_number))
+            }
+            _ => {}
+        }
+
+        let (max_speed, accel) = match arc {
+            Some(ref arc) if self.arc_tolerance.is_some() => {
+                return self
+                    .get_arc_segments(arc, move_type, warnlog)
+                    .chain_err(|| "Error splitting arc into segments");
+            }
+            Some(_) => self.get_arc_limits(),
+            None => self.get_line_limits(deltas, extra, dist),
+        };
+        let speed = self
+            .get_nominal_speed(move_type, max_speed, dist, warnlog)
+            .chain_err(|| "Error computing nominal speed")?;
+
+        let time = self.get_duration(dist, speed, accel);
 
         if log_enabled!(Level::Debug) {
             debug!("Duration: {}", time);
         }
 
-        Ok((time, dist))
+        Ok(vec![Block {
+            tool_number: None,
+            distance: dist,
+            duration: time,
+            speed,
+            accel,
+            entry_dir,
+            exit_dir,
+            path_mode: *self.path_mode,
+        }])
     }
 
-    fn get_distance(&'a self) -> Result<f32> {
-        if let Some(dest) = self.dest {
-            if dest.is_empty() {
-                return Ok(0.);
+    // Length of a feed move, 0 for rapids and groups without move
+    pub fn get_feed_length(&self) -> Result<f32> {
+        let direction = match (self.move_type, self.dest) {
+            (None, _) | (_, None) | (Some(MoveTypes::G0), _) => return Ok(0.),
+            (_, Some(ref dest)) if dest.is_empty() => return Ok(0.),
+            (Some(MoveTypes::G2), _) => Some(Direction::CW),
+            (Some(MoveTypes::G3), _) => Some(Direction::CCW),
+            _ => None,
+        };
+        let arc = match direction {
+            Some(d) => self.get_arc_geometry(d)?,
+            None => None,
+        };
+        let (dist, _, _) = match arc {
+            Some(ref arc) => self.get_dist_arc(arc)?,
+            None => self.get_dist_line()?,
+        };
+        Ok(dist)
+    }
+
+    fn check_travel(&'a self, deltas: (f32, f32, f32), warnlog: &mut Warnlog) {
+        let &(travel_x, travel_y, travel_z) = self.max_travel;
+        let (dx, dy, dz) = deltas;
+        for &(d, travel) in [(dx, travel_x), (dy, travel_y), (dz, travel_z)].iter() {
+            if let Some(t) = travel {
+                if d.abs() > t {
+                    warnlog.warn(WarnType::BeyondTravel);
+                }
             }
-        } else {
-            return Ok(0.);
         }
+    }
 
-        let move_type = match self.move_type {
-            &Some(ref mt) => match mt {
-                &GCode::MT(ref mt) => mt,
-                _ => return Ok(0.),
-            },
-            &None => return Ok(0.),
-        };
-        match move_type {
-            &MoveTypes::G0 | &MoveTypes::G1 => self.get_dist_line(),
-            &MoveTypes::G2 => self.get_dist_arc(Direction::CW),
-            &MoveTypes::G3 => self.get_dist_arc(Direction::CCW),
+    // Path speed (mm/min) and acceleration (mm/s²) allowed by the axes,
+    // for a move of length dist.
+    fn get_line_limits(
+        &'a self,
+        deltas: (f32, f32, f32),
+        extra: [f32; 6],
+        dist: f32,
+    ) -> (f32, f32) {
+        let linear = (deltas.0.powi(2) + deltas.1.powi(2) + deltas.2.powi(2)).sqrt();
+        let (mut speed, mut accel) = (::std::f32::INFINITY, ::std::f32::INFINITY);
+        if linear > 0. {
+            // Other axes can make the path longer than the XYZ travel
+            speed = path_limit(deltas, *self.max_speed) * dist / linear;
+            if let &Some(max_accel) = self.max_accel {
+                accel = path_limit(deltas, max_accel) * dist / linear;
+            }
+        }
+
+        for (d, axis) in extra.iter().zip(self.extra_axes.iter()) {
+            if let (true, &Some(ref axis)) = (*d != 0., axis) {
+                speed = speed.min(axis.max_speed * dist / d.abs());
+                if let Some(a) = axis.max_accel {
+                    accel = accel.min(a * dist / d.abs());
+                }
+            }
         }
+        (speed, accel)
     }
 
-    fn get_duration(&'a self, dist: f32, warnlog: &mut Warnlog) -> Result<f32> {
-        let move_type = match self.move_type {
-            &Some(ref mt) => match mt {
-                &GCode::MT(ref mt) => mt,
-                _ => return Ok(0.),
-            },
-            &None => return Ok(0.),
+    fn get_arc_limits(&'a self) -> (f32, f32) {
+        let (ms_u, ms_v, _) = self.plane.project(*self.max_speed);
+        let accel = match self.max_accel {
+            &Some(max_accel) => {
+                let (ac_u, ac_v, _) = self.plane.project(max_accel);
+                ac_u.min(ac_v)
+            }
+            &None => ::std::f32::INFINITY,
         };
+        (ms_u.min(ms_v), accel)
+    }
 
-        let min_duration = self
-            .get_durat_g0()
-            .chain_err(|| "Error computing travel duration for G0 group")?;
-
-        match move_type {
-            &MoveTypes::G0 => Ok(min_duration),
-            &MoveTypes::G1 => match self.speed {
-                &Some(ref s) => {
-                    let g1_duration = dist / s * 60.;
-                    let mut max_duration = g1_duration;
-                    if g1_duration < min_duration {
-                        max_duration = min_duration;
-                        warnlog.warn(WarnType::TooFast);
-                    }
-                    return Ok(max_duration);
+    // Feed in mm/min for a move of the given length. Inverse time moves
+    // last 60/F seconds, whatever their length.
+    fn get_feed(&'a self, dist: f32) -> Result<Option<f32>> {
+        let feed = match (&self.feed_mode, &self.speed) {
+            (_, &None) => None,
+            (&FeedMode::UnitsPerMinute, &Some(f)) => Some(f),
+            (&FeedMode::InverseTime, &Some(f)) => Some(f * self.feed_length.unwrap_or(dist)),
+            (&FeedMode::UnitsPerRev, &Some(f)) => match (self.spindle.dir, self.spindle.rpm) {
+                (SpindleDir::Off, _) | (_, None) => {
+                    bail!("Feed per revolution without spindle speed")
                 }
-                &None => bail!("No speed set for move of type: G1"),
+                (_, Some(rpm)) => Some(f * rpm),
             },
-            ref m => match self.speed {
-                &Some(ref s) => {
-                    let arc_duration = dist / s * 60.;
-                    let mut max_duration = arc_duration;
-                    if arc_duration < min_duration {
-                        max_duration = min_duration;
-                        warnlog.warn(WarnType::TooFast);
-                    }
-                    return Ok(max_duration);
+        };
+        Ok(feed)
+    }
+
+    // Returns the nominal speed in mm/s. dist is the length of the whole
+    // move, for inverse time feeds.
+    fn get_nominal_speed(
+        &'a self,
+        move_type: &MoveTypes,
+        max_speed: f32,
+        dist: f32,
+        warnlog: &mut Warnlog,
+    ) -> Result<f32> {
+        let speed = match move_type {
+            &MoveTypes::G0 => max_speed,
+            ref m => match self.get_feed(dist)? {
+                Some(s) if s > max_speed => {
+                    warnlog.warn(WarnType::TooFast);
+                    max_speed
                 }
-                &None => bail!(format!("No speed set for move of type: {:?}", m)),
+                Some(s) => s,
+                None => bail!(format!("No speed set for move of type: {:?}", m)),
             },
+        };
+
+        if log_enabled!(Level::Trace) {
+            trace!("Nominal speed: {}", speed);
         }
+
+        Ok(speed / 60.)
     }
 
-    fn get_durat_g0(&self) -> Result<f32> {
-        let (delta_x, delta_y, delta_z) =
-            self.get_deltas().chain_err(|| "Error computing deltas")?;
-        let &(ms_x, ms_y, ms_z) = self.max_speed;
+    // Splits an arc into chords the way Grbl does, so that each chord deviates
+    // from the arc by less than the arc tolerance.
+    fn get_arc_segments(
+        &'a self,
+        arc: &ArcGeometry,
+        move_type: &MoveTypes,
+        warnlog: &mut Warnlog,
+    ) -> Result<Vec<Block>> {
+        let tolerance = self.arc_tolerance.unwrap_or(0.);
+
+        let mut segments = 1;
+        if 2. * arc.radius > tolerance {
+            let n = (0.5 * arc.theta * arc.radius
+                / (tolerance * (2. * arc.radius - tolerance)).sqrt())
+            .floor();
+            segments = (n as usize).max(1);
+        }
+
+        let length = ((arc.radius * arc.theta).powi(2) + arc.helix.powi(2)).sqrt();
+        let (u0, v0) = (arc.cp.x.unwrap_or(0.), arc.cp.y.unwrap_or(0.));
+        let step = arc.sign * arc.theta / segments as f32;
+        let mut prev = (u0, v0);
+        let mut blocks = Vec::with_capacity(segments);
+
+        for n in 1..segments + 1 {
+            let next = if n == segments {
+                (arc.cd.x.unwrap_or(0.), arc.cd.y.unwrap_or(0.))
+            } else {
+                let (sin, cos) = (step * n as f32).sin_cos();
+                (u0 * cos - v0 * sin, u0 * sin + v0 * cos)
+            };
+            let deltas = self.plane.unproject((
+                next.0 - prev.0,
+                next.1 - prev.1,
+                arc.helix / segments as f32,
+            ));
+            prev = next;
 
-        let dura_x = delta_x.abs() / ms_x * 60.;
-        let dura_y = delta_y.abs() / ms_y * 60.;
-        let dura_z = delta_z.abs() / ms_z * 60.;
-        let max_duration = dura_x.max(dura_y).max(dura_z);
+            let dist = (deltas.0.powi(2) + deltas.1.powi(2) + deltas.2.powi(2)).sqrt();
+            if dist == 0. {
+                continue;
+            }
+            let dir = (deltas.0 / dist, deltas.1 / dist, deltas.2 / dist);
+            let (max_speed, accel) = self.get_line_limits(deltas, [0.; 6], dist);
+            let speed = self
+                .get_nominal_speed(move_type, max_speed, length, warnlog)
+                .chain_err(|| "Error computing nominal speed")?;
+
+            blocks.push(Block {
+                tool_number: None,
+                distance: dist,
+                duration: self.get_duration(dist, speed, accel),
+                speed,
+                accel,
+                entry_dir: dir,
+                exit_dir: dir,
+                path_mode: *self.path_mode,
+            });
+        }
 
         if log_enabled!(Level::Trace) {
-            trace!("G0 duration: {}", max_duration);
+            trace!("Arc split into {} segments", blocks.len());
         }
 
-        Ok(max_duration)
+        Ok(blocks)
+    }
+
+    fn get_duration(&'a self, dist: f32, speed: f32, accel: f32) -> f32 {
+        if dist == 0. {
+            return 0.;
+        }
+        trapezoid_duration(dist, 0., speed, 0., accel)
     }
 
     fn get_deltas(&self) -> Result<(f32, f32, f32)> {
@@ -120,33 +306,21 @@ impl<'a> ModalGroup<'a> {
         };
         let delta_x = match dest.x {
             Some(x) => match self.origin.x {
-                Some(ox) => match self.reference {
-                    &Some(Referential::Absolute) => x - ox,
-                    &Some(Referential::Increment) => x,
-                    &None => bail!("No referential set"),
-                },
+                Some(ox) => x - ox,
                 None => bail!("X origin not initialized"),
             },
             None => 0.,
         };
         let delta_y = match dest.y {
             Some(y) => match self.origin.y {
-                Some(oy) => match self.reference {
-                    &Some(Referential::Absolute) => y - oy,
-                    &Some(Referential::Increment) => y,
-                    &None => bail!("No referential set"),
-                },
+                Some(oy) => y - oy,
                 None => bail!("y origin not initialized"),
             },
             None => 0.,
         };
         let delta_z = match dest.z {
             Some(z) => match self.origin.z {
-                Some(oz) => match self.reference {
-                    &Some(Referential::Absolute) => z - oz,
-                    &Some(Referential::Increment) => z,
-                    &None => bail!("No referential set"),
-                },
+                Some(oz) => z - oz,
                 None => bail!("z origin not initialized"),
             },
             None => 0.,
@@ -154,7 +328,48 @@ impl<'a> ModalGroup<'a> {
         Ok((delta_x, delta_y, delta_z))
     }
 
-    fn get_dist_line(&self) -> Result<f32> {
+    // Travel of the axes besides X, Y and Z, rotary ones in degrees
+    fn get_extra_deltas(&self) -> [f32; 6] {
+        let mut deltas = [0.; 6];
+        let dest = match self.dest {
+            Some(d) => d,
+            None => return deltas,
+        };
+        for (i, delta) in deltas.iter_mut().enumerate() {
+            if let (Some(to), Some(from)) = (dest.extra[i], self.origin.extra[i]) {
+                *delta = to - from;
+            }
+        }
+        deltas
+    }
+
+    // As in LinuxCNC, feeds apply to XYZ, else to UVW, else to the rotary
+    // axes in deg/min. Rotations with a radius always count as the arc
+    // travelled at that radius.
+    fn get_path_length(&self, linear: f32) -> f32 {
+        let mut parallel = 0.;
+        let mut arcs = 0.;
+        let mut angles = 0.;
+        let deltas = self.get_extra_deltas();
+        for ((axis, d), settings) in EXTRA_AXES
+            .iter()
+            .zip(deltas.iter())
+            .zip(self.extra_axes.iter())
+        {
+            match settings.and_then(|s| s.radius) {
+                _ if !axis.is_rotary() => parallel = parallel + d.powi(2),
+                Some(r) => arcs = arcs + (d.to_radians() * r).powi(2),
+                None => angles = angles + d.powi(2),
+            }
+        }
+        let primary = if linear > 0. { linear } else { parallel.sqrt() };
+        if primary == 0. && arcs == 0. {
+            return angles.sqrt();
+        }
+        (primary.powi(2) + arcs).sqrt()
+    }
+
+    fn get_dist_line(&self) -> Result<(f32, UnitVector, UnitVector)> {
         let (delta_x, delta_y, delta_z) =
             self.get_deltas().chain_err(|| "Error computing deltas")?;
 
@@ -167,48 +382,67 @@ impl<'a> ModalGroup<'a> {
             );
         }
 
-        let dist = (delta_x.powi(2) + delta_y.powi(2) + delta_z.powi(2)).sqrt();
-        Ok(dist)
+        let linear = (delta_x.powi(2) + delta_y.powi(2) + delta_z.powi(2)).sqrt();
+        let dist = self.get_path_length(linear);
+        let dir = if dist > 0. {
+            (delta_x / dist, delta_y / dist, delta_z / dist)
+        } else {
+            (0., 0., 0.)
+        };
+        Ok((dist, dir, dir))
     }
 
-    fn get_dist_arc(&self, dir: Direction) -> Result<f32> {
+    // Returns None for degenerate arcs, which have a zero radius.
+    fn get_arc_geometry(&self, dir: Direction) -> Result<Option<ArcGeometry>> {
         let dest = match self.dest {
             Some(d) => d,
-            None => return Ok(0.),
+            None => bail!("No arc destination set"),
         };
-        let reference = match self.reference {
-            &Some(ref r) => r,
-            &None => bail!("No referential set"),
+        let clockwise = match dir {
+            Direction::CW => true,
+            Direction::CCW => false,
         };
-        let (cp, cd, dz) = self
+        let dest = self
             .origin
-            .to_rad_vec(&dest, reference)
+            .radius_to_offsets(&dest, self.plane, clockwise, self.unit)
+            .chain_err(|| "Error computing radius format arc center")?;
+        let (cp, cd, helix) = self
+            .origin
+            .to_rad_vec(&dest, self.plane)
             .chain_err(|| "Error getting radius vectors")?;
 
         let n_cp = cp.norm();
         let n_cd = cd.norm();
         let radius = (n_cp + n_cd) / 2.;
 
-        let mut theta = (cp.scalar_product(&cd) / (n_cp * n_cd)).acos();
-        if theta.is_nan() {
-            bail!("Error while computing angle between radius vectors");
-        }
-
-        match dir {
-            Direction::CW if cp.cross_product(&cd).is_sign_positive() => theta = 2. * PI - theta,
-            Direction::CCW if cp.cross_product(&cd).is_sign_negative() => theta = 2. * PI - theta,
-            _ => {}
+        if n_cp < MIN_ARC_RADIUS || n_cd < MIN_ARC_RADIUS {
+            return Ok(None);
         }
 
-        if theta == 0. {
-            theta = 2. * PI;
-        }
+        let sign = match dir {
+            Direction::CW => -1.,
+            Direction::CCW => 1.,
+        };
 
-        let dist = ((radius * theta).powi(2) + dz.powi(2)).sqrt();
+        let chord = ((cd.x.unwrap_or(0.) - cp.x.unwrap_or(0.)).powi(2)
+            + (cd.y.unwrap_or(0.) - cp.y.unwrap_or(0.)).powi(2))
+        .sqrt();
+        let mut theta = if chord < MIN_ARC_RADIUS {
+            // Start and end points are the same: full circle
+            2. * PI
+        } else {
+            let angle = sign * cp.cross_product(&cd).atan2(cp.scalar_product(&cd));
+            if angle <= 0. {
+                angle + 2. * PI
+            } else {
+                angle
+            }
+        };
+        theta = theta + 2. * PI * (self.turns - 1) as f32;
 
         if log_enabled!(Level::Trace) {
             trace!(
-                "get_dist_arc:\n    \
+                "get_arc_geometry:\n    \
                 cp:{:?}\n    \
                 cd:{:?}\n    \
                 radius:{}\n    \
@@ -220,6 +454,29 @@ impl<'a> ModalGroup<'a> {
             );
         }
 
-        Ok(dist)
+        Ok(Some(ArcGeometry {
+            cp,
+            cd,
+            helix,
+            radius,
+            theta,
+            sign,
+        }))
+    }
+
+    fn get_dist_arc(&self, arc: &ArcGeometry) -> Result<(f32, UnitVector, UnitVector)> {
+        let dist = ((arc.radius * arc.theta).powi(2) + arc.helix.powi(2)).sqrt();
+
+        let planar = arc.radius * arc.theta / dist;
+        let tangent = |v: &Coord| {
+            let n = v.norm();
+            self.plane.unproject((
+                -arc.sign * v.y.unwrap_or(0.) / n * planar,
+                arc.sign * v.x.unwrap_or(0.) / n * planar,
+                arc.helix / dist,
+            ))
+        };
+
+        Ok((dist, tangent(&arc.cp), tangent(&arc.cd)))
     }
 }
@@ -0,0 +1,276 @@
+use super::errors::*;
+use super::setup::DwellUnit;
+use crate::objects_def::*;
+
+// Clearance kept above the previous depth when G83 plunges back into the
+// hole, and G73 chip breaking retract, as in LinuxCNC (0.010 inch).
+const PECK_CLEARANCE: f32 = 0.254;
+
+// Feed is None for moves of the line being read
+pub struct Motion {
+    pub move_type: MoveTypes,
+    pub dest: Coord,
+    pub feed: Option<LineFeed>,
+    pub delays: Vec<Delay>,
+}
+
+// Moves of a cycle in the (u, v, w) program coordinates of the selected
+// plane, w being the drilling axis. Delays are held until the next move.
+struct Expansion {
+    plane: Plane,
+    offset: (f32, f32, f32),
+    pos: (f32, f32, f32),
+    motions: Vec<Motion>,
+    delays: Vec<Delay>,
+}
+impl Expansion {
+    fn go(&mut self, move_type: MoveTypes, u: f32, v: f32, w: f32) {
+        if (u, v, w) == self.pos {
+            return;
+        }
+        let (x, y, z) = self.plane.unproject((u, v, w));
+        let (ox, oy, oz) = self.offset;
+        let mut dest = Coord::new();
+        dest.x = Some(x + ox);
+        dest.y = Some(y + oy);
+        dest.z = Some(z + oz);
+        self.motions.push(Motion {
+            move_type,
+            dest,
+            feed: None,
+            delays: self.delays.drain(..).collect(),
+        });
+        self.pos = (u, v, w);
+    }
+
+    fn go_to_level(&mut self, move_type: MoveTypes, w: f32) {
+        let (u, v, _) = self.pos;
+        self.go(move_type, u, v, w);
+    }
+
+    fn wait(&mut self, kind: DelayKind, duration: f32) {
+        if duration > 0. {
+            self.delays.push(Delay { kind, duration });
+        }
+    }
+}
+
+impl Machine {
+    // Turns a canned cycle call into its moves, with absolute destinations.
+    // Returns the delays left after the last move.
+    pub fn expand_cycle(
+        &mut self,
+        cycle: CycleType,
+        dest: &Coord,
+        p: Option<f32>,
+        q: Option<f32>,
+        l: Option<u32>,
+    ) -> Result<(Vec<Motion>, Vec<Delay>)> {
+        let scale = match self.unit {
+            Some(Unit::Inch) => 25.4,
+            _ => 1.,
+        };
+        let incremental = match self.reference {
+            Some(Referential::Absolute) => false,
+            Some(Referential::Increment) => true,
+            None => bail!("No referential set"),
+        };
+
+        let current = self.offsets.current();
+        let offset = (
+            current.x.unwrap_or(0.),
+            current.y.unwrap_or(0.),
+            current.z.unwrap_or(0.),
+        );
+        let start = (
+            self.pos.x.unwrap_or(0.) - offset.0,
+            self.pos.y.unwrap_or(0.) - offset.1,
+            self.pos.z.unwrap_or(0.) - offset.2,
+        );
+        let (u0, v0, w0) = self.plane.project(start);
+        let (word_u, word_v, word_w) = self.plane.project((dest.x, dest.y, dest.z));
+
+        // R, Q, P and the depth are kept for the next cycles of the series
+        if dest.r.is_some() {
+            self.cycle.r = dest.r;
+        }
+        if word_w.is_some() {
+            self.cycle.bottom = word_w;
+        }
+        if let Some(q) = q {
+            self.cycle.peck = Some(q * scale);
+        }
+        if p.is_some() {
+            self.cycle.dwell = p;
+        }
+        let initial = *self.cycle.initial_level.get_or_insert(w0);
+
+        let r_level = match self.cycle.r {
+            Some(r) if incremental => w0 + r,
+            Some(r) => r,
+            None => bail!("Canned cycle without R word"),
+        };
+        let bottom = match self.cycle.bottom {
+            Some(b) if incremental => r_level + b,
+            Some(b) => b,
+            None => bail!("Canned cycle without depth"),
+        };
+        if bottom > r_level {
+            bail!("Canned cycle depth above the R plane");
+        }
+        let peck = match (cycle, self.cycle.peck) {
+            (CycleType::G73, Some(q)) | (CycleType::G83, Some(q)) if q > 0. => q,
+            (CycleType::G73, _) | (CycleType::G83, _) => {
+                bail!("Peck cycle without a positive Q word")
+            }
+            _ => 0.,
+        };
+        let dwell = match (self.cycle.dwell, self.dwell_unit) {
+            (Some(p), DwellUnit::Seconds) => p,
+            (Some(p), DwellUnit::Milliseconds) => p / 1000.,
+            (None, _) => 0.,
+        };
+        let clear = match self.retract_mode {
+            RetractMode::OldZ => initial.max(r_level),
+            RetractMode::RPlane => r_level,
+        };
+
+        let mut path = Expansion {
+            plane: self.plane,
+            offset,
+            pos: (u0, v0, w0),
+            motions: Vec::new(),
+            delays: Vec::new(),
+        };
+
+        // Preliminary move up to the R plane
+        if w0 < r_level {
+            path.go_to_level(MoveTypes::G0, r_level);
+        }
+
+        for _ in 0..l.unwrap_or(1) {
+            let (u, v) = if incremental {
+                (
+                    path.pos.0 + word_u.unwrap_or(0.),
+                    path.pos.1 + word_v.unwrap_or(0.),
+                )
+            } else {
+                (word_u.unwrap_or(path.pos.0), word_v.unwrap_or(path.pos.1))
+            };
+            let w = path.pos.2;
+            path.go(MoveTypes::G0, u, v, w);
+
+            match cycle {
+                CycleType::G87 => {}
+                _ => path.go_to_level(MoveTypes::G0, r_level),
+            }
+
+            match cycle {
+                CycleType::G73 => {
+                    let mut depth = r_level;
+                    while depth > bottom {
+                        if depth < r_level {
+                            path.go_to_level(MoveTypes::G0, depth + PECK_CLEARANCE);
+                        }
+                        depth = (depth - peck).max(bottom);
+                        path.go_to_level(MoveTypes::G1, depth);
+                    }
+                }
+                CycleType::G83 => {
+                    let mut depth = r_level;
+                    while depth > bottom {
+                        if depth < r_level {
+                            path.go_to_level(MoveTypes::G0, r_level);
+                            path.go_to_level(MoveTypes::G0, depth + PECK_CLEARANCE);
+                        }
+                        depth = (depth - peck).max(bottom);
+                        path.go_to_level(MoveTypes::G1, depth);
+                    }
+                }
+                CycleType::G82 => {
+                    path.go_to_level(MoveTypes::G1, bottom);
+                    path.wait(DelayKind::Dwell, dwell);
+                }
+                CycleType::G84 | CycleType::G85 => {
+                    path.go_to_level(MoveTypes::G1, bottom);
+                    path.go_to_level(MoveTypes::G1, r_level);
+                }
+                CycleType::G86 => {
+                    path.go_to_level(MoveTypes::G1, bottom);
+                    let dir = self.spindle.dir;
+                    path.wait(
+                        DelayKind::Spindle,
+                        self.spindle.update(Some(SpindleDir::Off), None),
+                    );
+                    path.go_to_level(MoveTypes::G0, clear);
+                    path.wait(DelayKind::Spindle, self.spindle.update(Some(dir), None));
+                }
+                CycleType::G88 => {
+                    // The operator jogs the tool out of the hole
+                    path.go_to_level(MoveTypes::G1, bottom);
+                    path.wait(DelayKind::Dwell, dwell);
+                    let dir = self.spindle.dir;
+                    path.wait(
+                        DelayKind::Spindle,
+                        self.spindle.update(Some(SpindleDir::Off), None),
+                    );
+                    path.wait(DelayKind::Pause, self.pause_time);
+                    path.go_to_level(MoveTypes::G0, clear);
+                    path.wait(DelayKind::Spindle, self.spindle.update(Some(dir), None));
+                }
+                CycleType::G89 => {
+                    path.go_to_level(MoveTypes::G1, bottom);
+                    path.wait(DelayKind::Dwell, dwell);
+                    path.go_to_level(MoveTypes::G1, r_level);
+                }
+                CycleType::G87 => self
+                    .back_bore(&mut path, dest, bottom, clear, incremental)
+                    .chain_err(|| "Error expanding G87 back boring")?,
+                CycleType::G81 => path.go_to_level(MoveTypes::G1, bottom),
+            }
+            path.go_to_level(MoveTypes::G0, clear);
+        }
+
+        Ok((path.motions, path.delays))
+    }
+
+    // The tool enters the hole off-center with the spindle stopped, then
+    // bores upwards from the bottom to the K level.
+    fn back_bore(
+        &mut self,
+        path: &mut Expansion,
+        dest: &Coord,
+        bottom: f32,
+        clear: f32,
+        incremental: bool,
+    ) -> Result<()> {
+        let (offset_u, offset_v, word_k) = self.plane.project((dest.i, dest.j, dest.k));
+        let top = match word_k {
+            Some(k) if incremental => bottom + k,
+            Some(k) => k,
+            None => bail!("G87 without K word"),
+        };
+        let (u, v, w) = path.pos;
+        let (off_u, off_v) = (u + offset_u.unwrap_or(0.), v + offset_v.unwrap_or(0.));
+        let dir = self.spindle.dir;
+
+        path.wait(
+            DelayKind::Spindle,
+            self.spindle.update(Some(SpindleDir::Off), None),
+        );
+        path.go(MoveTypes::G0, off_u, off_v, w);
+        path.go(MoveTypes::G0, off_u, off_v, bottom);
+        path.go(MoveTypes::G0, u, v, bottom);
+        path.wait(DelayKind::Spindle, self.spindle.update(Some(dir), None));
+        path.go(MoveTypes::G1, u, v, top);
+        path.wait(
+            DelayKind::Spindle,
+            self.spindle.update(Some(SpindleDir::Off), None),
+        );
+        path.go(MoveTypes::G0, off_u, off_v, top);
+        path.go(MoveTypes::G0, off_u, off_v, clear);
+        path.go(MoveTypes::G0, u, v, clear);
+        path.wait(DelayKind::Spindle, self.spindle.update(Some(dir), None));
+        Ok(())
+    }
+}
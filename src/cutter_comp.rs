@@ -0,0 +1,528 @@
+use super::errors::*;
+use crate::canned_cycles::Motion;
+use crate::objects_def::*;
+
+// Lengths (mm) below which points are merged and directions are parallel
+const EPSILON: f32 = 1e-4;
+
+type Point = (f32, f32);
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+// Element of the tool center path in the XY plane, in machine coordinates.
+// The programmed end point and the path direction there are kept to join
+// the next element. An element not joined to the previous one starts with
+// a straight lead from the tool position, at the height lead_z.
+#[derive(Debug, Copy)]
+pub struct Segment {
+    move_type: MoveTypes,
+    start: Point,
+    end: Point,
+    z: f32,
+    center: Option<Point>,
+    corner: Point,
+    exit_dir: Point,
+    lead_z: Option<f32>,
+    feed: LineFeed,
+}
+impl Clone for Segment {
+    fn clone(&self) -> Segment {
+        *self
+    }
+}
+
+// Cutter radius compensation state. The last element is held back until
+// the next one is known, since the corner between them moves its end.
+#[derive(Debug)]
+pub struct CutterComp {
+    pub side: Option<Side>,
+    pub radius: f32,
+    started: bool,
+    pending: Option<Segment>,
+}
+impl CutterComp {
+    pub fn new() -> CutterComp {
+        CutterComp {
+            side: None,
+            radius: 0.,
+            started: false,
+            pending: None,
+        }
+    }
+
+    // G41/G42 and G40: the next move is a lead-in or lead-out move
+    pub fn set(&mut self, side: Option<Side>, radius: f32) {
+        self.side = side;
+        self.radius = radius;
+        self.started = false;
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    // Releases the element held back, ending at its offset end point
+    pub fn flush(&mut self) -> Vec<Motion> {
+        self.pending.take().map(to_motions).unwrap_or_default()
+    }
+
+    // Offsets a programmed move going from start to dest, and returns the
+    // tool center moves now known. tool is the current tool center. Moves
+    // joining two elements keep the feed of the element they end.
+    fn push(
+        &mut self,
+        move_type: MoveTypes,
+        start: &Coord,
+        dest: &Coord,
+        tool: &Coord,
+        feed: LineFeed,
+    ) -> Result<Vec<Motion>> {
+        let side = match self.side {
+            Some(s) => s,
+            None => bail!("Cutter compensation is off"),
+        };
+        let sign = match side {
+            Side::Left => 1.,
+            Side::Right => -1.,
+        };
+        let offset = sign * self.radius;
+        let p0 = (start.x.unwrap_or(0.), start.y.unwrap_or(0.));
+        let p1 = (dest.x.unwrap_or(p0.0), dest.y.unwrap_or(p0.1));
+        let z = dest.z.or(start.z).unwrap_or(0.);
+        let tool_xy = (tool.x.unwrap_or(0.), tool.y.unwrap_or(0.));
+        let center = match move_type {
+            MoveTypes::G2 | MoveTypes::G3 => {
+                Some((p0.0 + dest.i.unwrap_or(0.), p0.1 + dest.j.unwrap_or(0.)))
+            }
+            _ => None,
+        };
+
+        // Moves along Z only do not change the path direction
+        if center.is_none() && norm(sub(p1, p0)) < EPSILON {
+            let mut motions = self.flush();
+            let xy = match motions.last() {
+                Some(m) => (m.dest.x.unwrap_or(0.), m.dest.y.unwrap_or(0.)),
+                None => tool_xy,
+            };
+            motions.push(line_motion(move_type, xy, z, feed));
+            return Ok(motions);
+        }
+
+        if !self.started {
+            // Lead-in: from the current tool position to the offset end
+            if center.is_some() {
+                bail!("Cutter compensation cannot start on an arc");
+            }
+            let dir = normalize(sub(p1, tool_xy));
+            self.pending = Some(Segment {
+                move_type,
+                start: tool_xy,
+                end: add(p1, scale(left(dir), offset)),
+                z,
+                center: None,
+                corner: p1,
+                exit_dir: dir,
+                lead_z: None,
+                feed,
+            });
+            self.started = true;
+            return Ok(Vec::new());
+        }
+
+        let (entry_dir, exit_dir) = match center {
+            Some(c) => {
+                let (r0, r1) = (sub(p0, c), sub(p1, c));
+                match move_type {
+                    MoveTypes::G2 => (normalize(right(r0)), normalize(right(r1))),
+                    _ => (normalize(left(r0)), normalize(left(r1))),
+                }
+            }
+            None => {
+                let dir = normalize(sub(p1, p0));
+                (dir, dir)
+            }
+        };
+        let mut next = Segment {
+            move_type,
+            start: add(p0, scale(left(entry_dir), offset)),
+            end: add(p1, scale(left(exit_dir), offset)),
+            z,
+            center,
+            corner: p1,
+            exit_dir,
+            lead_z: None,
+            feed,
+        };
+        if let Some(c) = center {
+            if norm(sub(next.start, c)) < EPSILON || dot(sub(next.start, c), sub(p0, c)) < 0. {
+                bail!("Tool radius too large for arc");
+            }
+        }
+
+        let mut motions = Vec::new();
+        match self.pending.take() {
+            Some(mut prev) => {
+                let gap = norm(sub(prev.end, next.start));
+                let turn = cross(prev.exit_dir, entry_dir);
+                let convex = sign * turn < -EPSILON
+                    || (turn.abs() <= EPSILON && dot(prev.exit_dir, entry_dir) < 0.);
+                if gap < EPSILON {
+                    motions.extend(to_motions(prev));
+                } else if convex {
+                    // The tool rolls around the programmed corner
+                    let corner_arc = Segment {
+                        move_type: match side {
+                            Side::Left => MoveTypes::G2,
+                            Side::Right => MoveTypes::G3,
+                        },
+                        start: prev.end,
+                        end: next.start,
+                        z: prev.z,
+                        center: Some(prev.corner),
+                        corner: prev.corner,
+                        exit_dir: entry_dir,
+                        lead_z: None,
+                        feed: prev.feed,
+                    };
+                    motions.extend(to_motions(prev));
+                    motions.extend(to_motions(corner_arc));
+                } else {
+                    // Concave corner: both elements stop where they cross
+                    match intersect(&prev, &next, prev.corner) {
+                        Some(point) => {
+                            prev.end = point;
+                            next.start = point;
+                            motions.extend(to_motions(prev));
+                        }
+                        None => {
+                            let (z, feed) = (prev.z, prev.feed);
+                            motions.extend(to_motions(prev));
+                            motions.push(line_motion(MoveTypes::G1, next.start, z, feed));
+                        }
+                    }
+                }
+            }
+            None => {
+                // Previous element already released, e.g. after a Z move
+                if norm(sub(tool_xy, next.start)) >= EPSILON {
+                    next.lead_z = Some(tool.z.unwrap_or(z));
+                }
+            }
+        }
+        self.pending = Some(next);
+        Ok(motions)
+    }
+}
+
+impl Machine {
+    // Moves of the tool center for a programmed move, with cutter
+    // compensation on. The programmed position moves to dest.
+    pub fn compensate(
+        &mut self,
+        move_type: MoveTypes,
+        dest: Coord,
+        turns: u32,
+        line_number: usize,
+    ) -> Result<Vec<Motion>> {
+        match self.plane {
+            Plane::XY => {}
+            _ => bail!("Cutter compensation is only supported in the XY plane"),
+        }
+        if turns > 1 {
+            bail!("Arcs with several turns cannot be compensated");
+        }
+        let dest = match move_type {
+            MoveTypes::G2 => self
+                .pos
+                .radius_to_offsets(&dest, &self.plane, true, &self.unit),
+            MoveTypes::G3 => self
+                .pos
+                .radius_to_offsets(&dest, &self.plane, false, &self.unit),
+            _ => Ok(dest),
+        }
+        .chain_err(|| "Error computing radius format arc center")?;
+        let feed = LineFeed {
+            speed: self.speed,
+            mode: self.feed_mode,
+            line_number,
+        };
+        let motions = self
+            .comp
+            .push(move_type, &self.pos, &dest, &self.center, feed)?;
+        self.pos.update(&dest);
+        Ok(motions)
+    }
+}
+
+// Moves of an element, after its lead if any
+fn to_motions(segment: Segment) -> Vec<Motion> {
+    let mut motions = Vec::new();
+    if let Some(z) = segment.lead_z {
+        motions.push(line_motion(MoveTypes::G1, segment.start, z, segment.feed));
+    }
+    let mut motion = line_motion(segment.move_type, segment.end, segment.z, segment.feed);
+    if let Some(c) = segment.center {
+        motion.dest.i = Some(c.0 - segment.start.0);
+        motion.dest.j = Some(c.1 - segment.start.1);
+    }
+    motions.push(motion);
+    motions
+}
+
+fn line_motion(move_type: MoveTypes, end: Point, z: f32, feed: LineFeed) -> Motion {
+    let mut dest = Coord::new();
+    dest.x = Some(end.0);
+    dest.y = Some(end.1);
+    dest.z = Some(z);
+    Motion {
+        move_type,
+        dest,
+        feed: Some(feed),
+        delays: Vec::new(),
+    }
+}
+
+// Crossing point of the two elements, taken as full lines or circles,
+// closest to the programmed corner
+fn intersect(a: &Segment, b: &Segment, near: Point) -> Option<Point> {
+    let points = match (a.center, b.center) {
+        (None, None) => line_line(a.start, a.end, b.start, b.end),
+        (Some(c), None) => line_circle(b.start, b.end, c, norm(sub(a.end, c))),
+        (None, Some(c)) => line_circle(a.start, a.end, c, norm(sub(b.start, c))),
+        (Some(c1), Some(c2)) => circle_circle(c1, norm(sub(a.end, c1)), c2, norm(sub(b.start, c2))),
+    };
+    points.into_iter().min_by(|p, q| {
+        norm(sub(*p, near))
+            .partial_cmp(&norm(sub(*q, near)))
+            .unwrap_or(::std::cmp::Ordering::Equal)
+    })
+}
+
+fn line_line(a0: Point, a1: Point, b0: Point, b1: Point) -> Vec<Point> {
+    let (da, db) = (sub(a1, a0), sub(b1, b0));
+    let den = cross(da, db);
+    if den.abs() < EPSILON * norm(da) * norm(db) {
+        return Vec::new();
+    }
+    let t = cross(sub(b0, a0), db) / den;
+    vec![add(a0, scale(da, t))]
+}
+
+fn line_circle(p0: Point, p1: Point, c: Point, r: f32) -> Vec<Point> {
+    let d = normalize(sub(p1, p0));
+    let f = sub(p0, c);
+    let b = dot(f, d);
+    let disc = b * b - (dot(f, f) - r * r);
+    if disc < 0. {
+        return Vec::new();
+    }
+    let s = disc.sqrt();
+    vec![add(p0, scale(d, -b - s)), add(p0, scale(d, -b + s))]
+}
+
+fn circle_circle(c1: Point, r1: f32, c2: Point, r2: f32) -> Vec<Point> {
+    let d = norm(sub(c2, c1));
+    if d < EPSILON || d > r1 + r2 || d < (r1 - r2).abs() {
+        return Vec::new();
+    }
+    let a = (r1 * r1 - r2 * r2 + d * d) / (2. * d);
+    let h = (r1 * r1 - a * a).max(0.).sqrt();
+    let axis = scale(sub(c2, c1), 1. / d);
+    let mid = add(c1, scale(axis, a));
+    vec![
+        add(mid, scale(left(axis), h)),
+        add(mid, scale(left(axis), -h)),
+    ]
+}
+
+fn add(a: Point, b: Point) -> Point {
+    (a.0 + b.0, a.1 + b.1)
+}
+
+fn sub(a: Point, b: Point) -> Point {
+    (a.0 - b.0, a.1 - b.1)
+}
+
+fn scale(a: Point, k: f32) -> Point {
+    (a.0 * k, a.1 * k)
+}
+
+fn dot(a: Point, b: Point) -> f32 {
+    a.0 * b.0 + a.1 * b.1
+}
+
+fn cross(a: Point, b: Point) -> f32 {
+    a.0 * b.1 - a.1 * b.0
+}
+
+fn norm(a: Point) -> f32 {
+    dot(a, a).sqrt()
+}
+
+fn normalize(a: Point) -> Point {
+    let n = norm(a);
+    if n > 0. {
+        scale(a, 1. / n)
+    } else {
+        (0., 0.)
+    }
+}
+
+// Normals of a direction, on its left and right
+fn left(a: Point) -> Point {
+    (-a.1, a.0)
+}
+
+fn right(a: Point) -> Point {
+    (a.1, -a.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gcode_lexer::tests::{machine, mill, run};
+    use crate::output::Warnlog;
+
+    fn to(x: f32, y: f32) -> Coord {
+        let mut dest = Coord::new();
+        dest.x = Some(x);
+        dest.y = Some(y);
+        dest
+    }
+
+    fn end(motion: &Motion) -> Point {
+        (motion.dest.x.unwrap(), motion.dest.y.unwrap())
+    }
+
+    fn assert_point(p: Point, expected: Point) {
+        assert!(norm(sub(p, expected)) < 1e-4, "{:?} != {:?}", p, expected);
+    }
+
+    // Left compensation of a 2mm tool, the tool center at the origin
+    fn left_comp() -> Machine {
+        let mut m = machine(mill());
+        m.comp.set(Some(Side::Left), 1.);
+        m
+    }
+
+    #[test]
+    fn lead_in_is_held_back() {
+        let mut m = left_comp();
+        assert!(m
+            .compensate(MoveTypes::G1, to(10., 0.), 1, 1)
+            .unwrap()
+            .is_empty());
+        assert!(m.comp.is_pending());
+        let lead_in = m.comp.flush();
+        assert_eq!(lead_in.len(), 1);
+        assert_point(end(&lead_in[0]), (10., 1.));
+    }
+
+    #[test]
+    fn convex_corner_gets_an_arc() {
+        // Turning right with the tool on the left goes around the corner
+        let mut m = left_comp();
+        m.compensate(MoveTypes::G1, to(10., 0.), 1, 1).unwrap();
+        let motions = m.compensate(MoveTypes::G1, to(10., -10.), 1, 1).unwrap();
+        assert_eq!(motions.len(), 2);
+        assert_point(end(&motions[0]), (10., 1.));
+        assert!(matches!(motions[1].move_type, MoveTypes::G2));
+        assert_point(end(&motions[1]), (11., 0.));
+        // Centered on the programmed corner
+        assert_point(
+            (motions[1].dest.i.unwrap(), motions[1].dest.j.unwrap()),
+            (0., -1.),
+        );
+        assert_point(end(&m.comp.flush()[0]), (11., -10.));
+    }
+
+    #[test]
+    fn concave_corner_is_trimmed() {
+        // Turning left with the tool on the left stops where offsets cross
+        let mut m = left_comp();
+        m.compensate(MoveTypes::G1, to(10., 0.), 1, 1).unwrap();
+        m.compensate(MoveTypes::G1, to(10., 10.), 1, 1).unwrap();
+        let motions = m.compensate(MoveTypes::G1, to(0., 10.), 1, 1).unwrap();
+        assert_eq!(motions.len(), 1);
+        assert_point(end(&motions[0]), (9., 9.));
+        assert_point(end(&m.comp.flush()[0]), (0., 9.));
+    }
+
+    #[test]
+    fn concave_corner_after_an_arc() {
+        // Quarter circle of radius 10 around (10, 10), then a 45° left turn
+        let mut m = left_comp();
+        m.compensate(MoveTypes::G1, to(10., 0.), 1, 1).unwrap();
+        let mut arc = to(20., 10.);
+        arc.i = Some(0.);
+        arc.j = Some(10.);
+        // Tangent to the lead-in: nothing to join
+        assert_eq!(m.compensate(MoveTypes::G3, arc, 1, 1).unwrap().len(), 1);
+        let motions = m.compensate(MoveTypes::G1, to(10., 20.), 1, 1).unwrap();
+        // The offset circle of radius 9 is cut short by the offset line
+        assert_eq!(motions.len(), 1);
+        assert!(matches!(motions[0].move_type, MoveTypes::G3));
+        assert_point(end(&motions[0]), (18.99088, 9.594908));
+        assert_point(
+            (motions[0].dest.i.unwrap(), motions[0].dest.j.unwrap()),
+            (0., 9.),
+        );
+    }
+
+    #[test]
+    fn entry_and_exit_moves() {
+        let mut m = machine(mill());
+        let program = "G21 G90 G17 F600
+G41.1 D2
+G1 X10 Y0
+G1 X10 Y10
+G40
+G1 X20 Y10";
+        let (blocks, _) = run(&mut m, program, &mut Warnlog::new()).unwrap();
+        let distances: Vec<f32> = blocks.iter().map(|b| b.distance).collect();
+        // Entry to the trimmed corner (9, 0.9), along X9, then exit from
+        // the offset end point
+        let expected = [(81f32 + 0.81).sqrt(), 9.1, 11.];
+        assert_eq!(distances.len(), expected.len());
+        for (d, e) in distances.iter().zip(expected.iter()) {
+            assert!((d - e).abs() < 1e-4, "{:?}", distances);
+        }
+    }
+
+    #[test]
+    fn held_back_moves_keep_their_feed() {
+        let mut m = machine(mill());
+        let program = "G21 G90 G17
+G41.1 D2
+G1 X10 Y0 F60
+G1 X10 Y10 F1200
+G40
+G1 X20 Y10 F600";
+        let (blocks, _) = run(&mut m, program, &mut Warnlog::new()).unwrap();
+        let durations: Vec<f32> = blocks.iter().map(|b| b.duration).collect();
+        let expected = [(81f32 + 0.81).sqrt(), 9.1 / 20., 11. / 10.];
+        assert_eq!(durations.len(), expected.len());
+        for (d, e) in durations.iter().zip(expected.iter()) {
+            assert!((d - e).abs() < 1e-4, "{:?}", durations);
+        }
+    }
+
+    #[test]
+    fn unsupported_moves() {
+        let mut m = left_comp();
+        let mut arc = to(10., 0.);
+        arc.i = Some(5.);
+        arc.j = Some(0.);
+        assert!(m.compensate(MoveTypes::G2, arc, 1, 1).is_err());
+
+        m.compensate(MoveTypes::G1, to(10., 0.), 1, 1).unwrap();
+        let mut full_circles = to(10., 0.);
+        full_circles.i = Some(5.);
+        full_circles.j = Some(0.);
+        assert!(m.compensate(MoveTypes::G2, full_circles, 2, 1).is_err());
+    }
+}
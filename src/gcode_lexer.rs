@@ -1,43 +1,85 @@
 use super::errors::*;
 use super::objects_def::*;
 use super::lineparser::*;
+use super::output::{Warnlog, WarnType};
+use super::setup::DwellUnit;
+use super::canned_cycles::Motion;
+use super::cutter_comp::Side;
+use super::tool_table::{tool_index, ToolEntry};
+
+// Decimal codes which are valid but have no effect on the duration
+const KNOWN_DECIMAL_GCODES: [(u8, u8); 8] = [
+    (5, 1), (5, 2), (5, 3), (33, 1), (38, 2), (38, 3), (38, 4), (38, 5),
+];
+
+struct Words {
+    d: Option<f32>,
+    h: Option<f32>,
+    l: Option<u32>,
+    p: Option<f32>,
+    q: Option<f32>,
+    s: Option<f32>,
+}
 
 impl Machine {
-    pub fn line_depacker(&mut self, line: Vec<Codes>) -> Result<(ModalGroup, &Option<u8>)> {
+    pub fn line_depacker(&mut self, line: Vec<Codes>, line_number: usize, warnlog: &mut Warnlog)
+                         -> Result<(Vec<ModalGroup>, &Option<u8>)> {
         let mut dest = Coord::new();
         let mut speed = None;
+        let mut gcodes = Vec::new();
+        let mut words = Words { d: None, h: None, l: None, p: None, q: None, s: None };
+        let mut delays = Vec::new();
+        let mut spindle_dir = None;
         for item in line {
             match item {
-                Codes::G(i) => {
-                    let gcode = g_tokenizer(i)
-                        .chain_err(|| "Error depacking G code")?;
-                    self.process_gcode(gcode);
-                },
-                Codes::M(i) => {
-                    if i == 30 {
-                        self.status = Status::EOP;
+                Codes::G(i, sub) => {
+                    match g_tokenizer(i, sub)
+                        .chain_err(|| "Error depacking G code")? {
+                        Some(gcode) => gcodes.push(gcode),
+                        None => warnlog.warn(WarnType::UnknownCode(
+                            format!("G{}.{}", i, sub.unwrap_or(0)), line_number)),
+                    }
+                },
+                Codes::M(i, sub) => {
+                    match sub {
+                        None if i == 30 => self.status = Status::EOP,
+                        None if i == 0 || i == 1 => delays.push(Delay {
+                            kind: DelayKind::Pause,
+                            duration: self.pause_time,
+                        }),
+                        None if i == 6 => delays.push(Delay {
+                            kind: DelayKind::ToolChange,
+                            duration: self.tool_change_time,
+                        }),
+                        None if i == 3 => spindle_dir = Some(SpindleDir::CW),
+                        None if i == 4 => spindle_dir = Some(SpindleDir::CCW),
+                        None if i == 5 => spindle_dir = Some(SpindleDir::Off),
+                        None if self.mcodes.contains_key(&i) => {
+                            *self.mcode_counts.entry(i).or_insert(0) += 1;
+                            delays.push(Delay {
+                                kind: DelayKind::Auxiliary(i),
+                                duration: self.mcodes[&i].delay,
+                            });
+                        },
+                        None => {},
+                        Some(s) => warnlog.warn(WarnType::UnknownCode(
+                            format!("M{}.{}", i, s), line_number)),
                     }
                 },
                 Codes::T(i) => {
                     self.tool_number = Some(i);
                 },
-                Codes::X(i) => {
-                    if let Some(_) = dest.x {
-                        bail!("Two X coordinate in the same modal group");
+                Codes::Axis(axis, i) => {
+                    if let Some(index) = axis.extra_index() {
+                        if self.extra_axes[index].is_none() {
+                            warnlog.warn(WarnType::UndeclaredAxis(axis, line_number));
+                            continue;
+                        }
                     }
-                    dest.x = Some(i);
-                },
-                Codes::Y(i) => {
-                    if let Some(_) = dest.y {
-                        bail!("Two Y coordinate in the same modal group");
-                    }
-                    dest.y = Some(i);
-                },
-                Codes::Z(i) => {
-                    if let Some(_) = dest.z {
-                        bail!("Two Z coordinate in the same modal group");
+                    if let Some(_) = dest.get(axis) {
+                        bail!(format!("Two {:?} coordinate in the same modal group", axis));
                     }
-                    dest.z = Some(i);
+                    dest.set(axis, Some(i));
                 },
                 Codes::I(i) => {
                     if let Some(_) = dest.i {
@@ -57,56 +99,515 @@ impl Machine {
                     }
                     dest.k = Some(i);
                 },
+                Codes::R(i) => {
+                    if let Some(_) = dest.r {
+                        bail!("Two R words in the same modal group");
+                    }
+                    dest.r = Some(i);
+                },
                 Codes::F(i) => speed = Some(i),
+                Codes::D(i) => {
+                    if let Some(_) = words.d {
+                        bail!("Two D words in the same modal group");
+                    }
+                    words.d = Some(i);
+                },
+                Codes::H(i) => {
+                    if let Some(_) = words.h {
+                        bail!("Two H words in the same modal group");
+                    }
+                    words.h = Some(i);
+                },
+                Codes::L(i) => {
+                    if let Some(_) = words.l {
+                        bail!("Two L words in the same modal group");
+                    }
+                    words.l = Some(i);
+                },
+                Codes::P(i) => {
+                    if let Some(_) = words.p {
+                        bail!("Two P words in the same modal group");
+                    }
+                    words.p = Some(i);
+                },
+                Codes::Q(i) => {
+                    if let Some(_) = words.q {
+                        bail!("Two Q words in the same modal group");
+                    }
+                    words.q = Some(i);
+                },
+                Codes::S(i) => {
+                    if let Some(_) = words.s {
+                        bail!("Two S words in the same modal group");
+                    }
+                    words.s = Some(i);
+                },
             }
         }
 
-        let (dest, speed) = self.convert_units(dest,speed)
+        // With G4 and no P word, S is a dwell time instead of a spindle speed
+        let dwell_s = words.p.is_none() && gcodes.iter().any(|g| match g {
+            &GCode::Dwell => true,
+            _ => false,
+        });
+        let rpm = if dwell_s { None } else { words.s };
+        let spindle_time = self.spindle.update(spindle_dir, rpm);
+        if spindle_time > 0. {
+            delays.push(Delay { kind: DelayKind::Spindle, duration: spindle_time });
+        }
+
+        // Non-modal codes using the axis words of the line
+        let mut set_g92 = false;
+        let mut set_tool_length = false;
+        let mut machine_coords = false;
+        let mut home = None;
+        for gcode in gcodes.iter() {
+            match gcode {
+                &GCode::Flag(Flags::G92) => set_g92 = true,
+                &GCode::Flag(Flags::G43_1) => set_tool_length = true,
+                &GCode::Flag(Flags::G53) => machine_coords = true,
+                &GCode::Flag(Flags::G28) => home = Some(self.homes.g28),
+                &GCode::Flag(Flags::G30) => home = Some(self.homes.g30),
+                _ => {},
+            }
+        }
+
+        // G4, G64 and canned cycles read the P word themselves
+        let p_taken = gcodes.iter().any(|g| match g {
+            &GCode::Dwell | &GCode::Cycle(_) | &GCode::Flag(Flags::G64) => true,
+            _ => false,
+        });
+
+        let prev_side = self.comp.side;
+        for gcode in gcodes {
+            self.process_gcode(gcode, &words, &mut delays)
+                .chain_err(|| format!("Error processing G code at line {}", line_number))?;
+        }
+
+        // P sets the number of full turns of G2/G3 arcs, on lines moving along
+        // one. Axis words of G92, G43.1 and G28/G30 do not make an arc.
+        let arc_line = !p_taken && !dest.is_empty() && !set_g92 && !set_tool_length
+            && home.is_none();
+        let turns = match (&self.move_type, words.p) {
+            (&Some(GCode::MT(MoveTypes::G2)), Some(p)) |
+            (&Some(GCode::MT(MoveTypes::G3)), Some(p)) if arc_line => {
+                if p < 1. || p.fract() != 0. {
+                    bail!(format!("Arc turn count must be a positive integer, found P{}", p));
+                }
+                p as u32
+            },
+            _ => 1,
+        };
+
+        let (mut dest, speed) = self.convert_units(dest,speed)
             .chain_err(|| "Error converting units")?;
 
+        if set_g92 {
+            self.set_g92(&dest);
+            dest = Coord::new();
+        }
+        if set_tool_length {
+            self.offsets.tool_length = dest.z.unwrap_or(0.);
+            dest = Coord::new();
+        }
+
+        // The element held back by the cutter compensation ends before any
+        // delay, at the end of the program and when the compensation changes
+        let comp_change = self.comp.side != prev_side;
+        let flushed = match self.status {
+            _ if comp_change || home.is_some() || !delays.is_empty() => self.comp.flush(),
+            Status::EOP => self.comp.flush(),
+            Status::Continue => Vec::new(),
+        };
+
+        // Inverse time F words are only valid for the move of their line
+        if self.feed_mode == FeedMode::InverseTime {
+            match self.move_type {
+                Some(GCode::MT(MoveTypes::G0)) => {},
+                Some(GCode::MT(_)) if !dest.is_empty() && speed.is_none() => {
+                    bail!(format!("Inverse time move without F word at line {}", line_number))
+                },
+                Some(GCode::Cycle(_)) if !dest.is_empty() => {
+                    bail!("Canned cycles cannot be used in inverse time feed mode")
+                },
+                _ => {},
+            }
+            self.speed = None;
+        }
         if let Some(s) = speed {
             self.speed = Some(s);
         }
+        self.check_tool_limits(line_number, warnlog);
 
-        let modgroup = ModalGroup {
-            move_type: &self.move_type,
-            origin: self.pos.clone(),
-            dest: Some(dest),
-            speed: &self.speed,
+        let (motions, trailing) = match (home, &self.move_type) {
+            (Some(_), _) if self.comp.side.is_some() => {
+                bail!("G28/G30 cannot be used with cutter compensation")
+            },
+            (Some(home), _) => {
+                let motions = self.home_return(home, &dest)
+                    .chain_err(|| "Error computing G28/G30 return")?;
+                (motions, Vec::new())
+            },
+            _ if dest.is_empty() => (Vec::new(), Vec::new()),
+            (None, &Some(GCode::MT(move_type))) => {
+                let dest = self.resolve_dest(&dest, machine_coords)
+                    .chain_err(|| "Error resolving destination")?;
+                match self.comp.side {
+                    Some(_) if machine_coords => {
+                        bail!("G53 cannot be used with cutter compensation")
+                    },
+                    Some(_) => {
+                        let motions = self.compensate(move_type, dest, turns, line_number)
+                            .chain_err(|| format!("Error compensating cutter radius at line {}",
+                                                  line_number))?;
+                        (motions, Vec::new())
+                    },
+                    None => {
+                        let motion = Motion { move_type, dest, feed: None, delays: Vec::new() };
+                        (vec![motion], Vec::new())
+                    },
+                }
+            },
+            (None, &Some(GCode::Cycle(_))) if self.comp.side.is_some() => {
+                bail!("Canned cycles cannot be used with cutter compensation")
+            },
+            (None, &Some(GCode::Cycle(cycle))) => {
+                self.expand_cycle(cycle, &dest, words.p, words.q, words.l)
+                    .chain_err(|| format!("Error expanding canned cycle at line {}", line_number))?
+            },
+            // After G80, or before any motion code
+            (None, &None) if dest.has_axes() => {
+                bail!(format!("Axis words without a motion mode at line {}", line_number))
+            },
+            _ => (Vec::new(), Vec::new()),
+        };
+
+        // Program stops happen after the moves, other delays before them
+        let (pauses, mut before): (Vec<Delay>, Vec<Delay>) = delays.into_iter()
+            .partition(|d| d.kind == DelayKind::Pause);
+        let mut steps: Vec<_> = flushed.into_iter()
+            .map(|m| (Some(m.move_type), Some(m.dest), m.feed, m.delays))
+            .collect();
+        for mut motion in motions {
+            before.append(&mut motion.delays);
+            steps.push((Some(motion.move_type), Some(motion.dest), motion.feed, before));
+            before = Vec::new();
+        }
+        before.extend(trailing);
+        before.extend(pauses);
+        if !before.is_empty() || steps.is_empty() {
+            steps.push((None, None, None, before));
+        }
+
+        // Moves start from the tool center, which only differs from the
+        // programmed position with cutter compensation. Moves released by the
+        // compensation keep the feed of their own line.
+        let line_feed = LineFeed { speed: self.speed, mode: self.feed_mode, line_number };
+        let mut origin = self.center.clone();
+        let mut groups = Vec::new();
+        for (move_type, dest, feed, delays) in steps {
+            let start = origin.clone();
+            if let Some(ref d) = dest {
+                origin.update(d);
+            }
+            groups.push((move_type, start, dest, feed.unwrap_or(line_feed), delays));
+        }
+        self.center = origin;
+        if self.comp.side.is_none() && !self.comp.is_pending() {
+            self.pos = self.center.clone();
+        }
+
+        let mut modgroups: Vec<ModalGroup> = groups.into_iter()
+            .map(|(move_type, origin, dest, feed, delays)| ModalGroup {
+            move_type,
+            origin,
+            dest,
+            speed: feed.speed,
+            feed_mode: feed.mode,
             max_speed: &self.max_speed,
+            max_accel: &self.max_accel,
+            arc_tolerance: &self.arc_tolerance,
+            max_travel: &self.max_travel,
+            extra_axes: &self.extra_axes,
             unit: &self.unit,
-            reference: &self.reference,
-        };
-        match modgroup.dest {
-            Some(ref dest) => {
-                self.pos.update(&dest);
+            path_mode: &self.path_mode,
+            plane: &self.plane,
+            spindle: &self.spindle,
+            turns,
+            feed_length: None,
+            line_number: feed.line_number,
+            delays,
+        }).collect();
+
+        // Moves split by the cutter compensation share the 1/F minute of
+        // their own line in proportion to their lengths
+        let mut lengths: Vec<(usize, f32)> = Vec::new();
+        for group in modgroups.iter().filter(|g| g.feed_mode == FeedMode::InverseTime) {
+            let length = group.get_feed_length()
+                .chain_err(|| format!("Error computing move length at line {}",
+                                      group.line_number))?;
+            match lengths.iter_mut().find(|l| l.0 == group.line_number) {
+                Some(l) => l.1 += length,
+                None => lengths.push((group.line_number, length)),
+            }
+        }
+        for group in modgroups.iter_mut() {
+            if let Some(l) = lengths.iter().find(|l| l.0 == group.line_number) {
+                group.feed_length = Some(l.1);
+            }
+        }
+        Ok((modgroups, &self.tool_number))
+    }
+
+    // Machine coordinates of the end point of a move. With G53, absolute
+    // coordinates are already given in machine coordinates.
+    fn resolve_dest(&self, dest: &Coord, machine_coords: bool) -> Result<Coord> {
+        let mut resolved = self.pos.clone();
+        let mut target = dest.clone();
+        match self.reference {
+            Some(Referential::Increment) if machine_coords => {
+                bail!("G53 cannot be used in incremental distance mode")
             },
-            None => {},
+            _ if machine_coords => {},
+            _ => target.add(&self.offsets.current()),
         }
-        Ok((modgroup, &self.tool_number))
+        match self.reference {
+            Some(Referential::Absolute) => {
+                resolved.update(&target);
+                for index in 0..resolved.extra.len() {
+                    resolved.extra[index] = self.rollover(index, self.pos.extra[index],
+                                                          resolved.extra[index]);
+                }
+            },
+            Some(Referential::Increment) => resolved.add(dest),
+            None if !dest.has_axes() => {},
+            None => bail!("No referential set"),
+        }
+        resolved.i = dest.i;
+        resolved.j = dest.j;
+        resolved.k = dest.k;
+        resolved.r = dest.r;
+        Ok(resolved)
+    }
+
+    // Rollover axes reach their absolute angle the shortest way
+    fn rollover(&self, axis: usize, from: Option<f32>, to: Option<f32>) -> Option<f32> {
+        match (from, to) {
+            (Some(f), Some(t)) if self.extra_axes[axis].map_or(false, |a| a.rollover) => {
+                let delta = (t - f).rem_euclid(360.);
+                if delta > 180. {
+                    Some(f + delta - 360.)
+                } else {
+                    Some(f + delta)
+                }
+            },
+            _ => to,
+        }
+    }
+
+    // Rapid move to the stored position, through the intermediate point given
+    // by the axis words. Only the axes given move to the stored position,
+    // all of them without axis words.
+    fn home_return(&self, home: (f32, f32, f32), dest: &Coord) -> Result<Vec<Motion>> {
+        let mut motions = Vec::new();
+        let mut end = Coord::new();
+        end.x = Some(home.0);
+        end.y = Some(home.1);
+        end.z = Some(home.2);
+
+        if dest.x.is_some() || dest.y.is_some() || dest.z.is_some() {
+            let mut axes = Coord::new();
+            axes.x = dest.x;
+            axes.y = dest.y;
+            axes.z = dest.z;
+            let intermediate = self.resolve_dest(&axes, false)
+                .chain_err(|| "Error resolving intermediate point")?;
+            let mut home_axes = intermediate.clone();
+            home_axes.x = dest.x.and(end.x);
+            home_axes.y = dest.y.and(end.y);
+            home_axes.z = dest.z.and(end.z);
+            end = intermediate.clone();
+            end.update(&home_axes);
+            motions.push(Motion { move_type: MoveTypes::G0, dest: intermediate, feed: None,
+                                  delays: Vec::new() });
+        }
+        motions.push(Motion { move_type: MoveTypes::G0, dest: end, feed: None, delays: Vec::new() });
+        Ok(motions)
+    }
+
+    // Offsets the axes given so that the current point gets their value
+    fn set_g92(&mut self, dest: &Coord) {
+        let (sx, sy, sz) = self.offsets.systems[self.offsets.active];
+        let sz = sz + self.offsets.tool_length;
+        let (x, y, z) = self.pos_tuple();
+        let g92 = &mut self.offsets.g92;
+        if let Some(u) = dest.x {
+            g92.0 = x - sx - u;
+        }
+        if let Some(u) = dest.y {
+            g92.1 = y - sy - u;
+        }
+        if let Some(u) = dest.z {
+            g92.2 = z - sz - u;
+        }
+        self.offsets.g92_saved = self.offsets.g92;
     }
 
-    fn process_gcode(&mut self, gcode: GCode) {
+    fn process_gcode(&mut self, gcode: GCode, words: &Words, delays: &mut Vec<Delay>)
+                     -> Result<()> {
         match gcode {
-            GCode::MT(_) => self.move_type = Some(gcode),
+            GCode::MT(_) => {
+                self.move_type = Some(gcode);
+                self.cycle = CannedCycle::new();
+            },
+            GCode::Cycle(_) => self.move_type = Some(gcode),
             GCode::Flag(f) => {
                 match f {
+                    Flags::G17 => self.plane = Plane::XY,
+                    Flags::G18 => self.plane = Plane::XZ,
+                    Flags::G19 => self.plane = Plane::YZ,
                     Flags::G20 => self.unit = Some(Unit::Inch),
                     Flags::G21 => self.unit = Some(Unit::MM),
+                    Flags::G61 => self.path_mode = PathMode::ExactPath,
+                    Flags::G61_1 => self.path_mode = PathMode::ExactStop,
+                    Flags::G64 => {
+                        let scale = unit_scale(&self.unit);
+                        self.path_mode = PathMode::Blending(
+                            words.p.map(|p| p * scale),
+                            words.q.map(|q| q * scale),
+                        );
+                    },
+                    Flags::G80 => {
+                        self.move_type = None;
+                        self.cycle = CannedCycle::new();
+                    },
+                    Flags::CoordSystem(i) => self.offsets.active = i,
+                    Flags::G28_1 => self.homes.g28 = self.pos_tuple(),
+                    Flags::G30_1 => self.homes.g30 = self.pos_tuple(),
+                    Flags::G28 | Flags::G30 | Flags::G53 => {},
+                    Flags::G40 => self.comp.set(None, 0.),
+                    Flags::G41 | Flags::G42 => {
+                        let tool = match (words.d, self.tool_number) {
+                            (Some(d), _) => tool_index(d)?,
+                            (None, Some(t)) => t,
+                            (None, None) => bail!("Cutter compensation without D word nor tool"),
+                        };
+                        let radius = self.tool_entry(tool)?.diameter / 2.;
+                        self.comp.set(Some(comp_side(&f)), radius);
+                    },
+                    Flags::G41_1 | Flags::G42_1 => {
+                        let diameter = match words.d {
+                            Some(d) => d * unit_scale(&self.unit),
+                            None => bail!("Dynamic cutter compensation without D word"),
+                        };
+                        self.comp.set(Some(comp_side(&f)), diameter / 2.);
+                    },
+                    Flags::G43 => {
+                        let tool = match (words.h, self.tool_number) {
+                            (Some(h), _) => tool_index(h)?,
+                            (None, Some(t)) => t,
+                            (None, None) => bail!("G43 without H word nor tool"),
+                        };
+                        self.offsets.tool_length = self.tool_entry(tool)?.length;
+                    },
+                    Flags::G43_1 => {},
+                    Flags::G43_2 => {
+                        let tool = match words.h {
+                            Some(h) => tool_index(h)?,
+                            None => bail!("G43.2 without H word"),
+                        };
+                        self.offsets.tool_length += self.tool_entry(tool)?.length;
+                    },
+                    Flags::G49 => self.offsets.tool_length = 0.,
                     Flags::G90 => self.reference = Some(Referential::Absolute),
                     Flags::G91 => self.reference = Some(Referential::Increment),
+                    Flags::G92 => {},
+                    Flags::G92_1 => {
+                        self.offsets.g92 = (0., 0., 0.);
+                        self.offsets.g92_saved = (0., 0., 0.);
+                    },
+                    Flags::G92_2 => self.offsets.g92 = (0., 0., 0.),
+                    Flags::G92_3 => self.offsets.g92 = self.offsets.g92_saved,
+                    Flags::G93 => self.set_feed_mode(FeedMode::InverseTime),
+                    Flags::G94 => self.set_feed_mode(FeedMode::UnitsPerMinute),
+                    Flags::G95 => self.set_feed_mode(FeedMode::UnitsPerRev),
+                    Flags::G98 => self.retract_mode = RetractMode::OldZ,
+                    Flags::G99 => self.retract_mode = RetractMode::RPlane,
+                }
+            },
+            GCode::Dwell => {
+                // Marlin also accepts the dwell time in seconds as S
+                let duration = match (words.p, words.s, self.dwell_unit) {
+                    (Some(p), _, DwellUnit::Seconds) => p,
+                    (Some(p), _, DwellUnit::Milliseconds) => p / 1000.,
+                    (None, Some(s), _) => s,
+                    (None, None, _) => bail!("G4 without P or S word"),
+                };
+                if duration < 0. {
+                    bail!(format!("Negative dwell time: {}s", duration));
                 }
+                delays.push(Delay { kind: DelayKind::Dwell, duration });
             },
             GCode::Dump => {},
         }
+        Ok(())
+    }
+
+    // Feed and spindle speed against the limits of the tool library
+    fn check_tool_limits(&self, line_number: usize, warnlog: &mut Warnlog) {
+        let (number, entry) = match self.tool_number {
+            Some(t) => match self.tools.get(&t) {
+                Some(entry) => (t, entry),
+                None => return,
+            },
+            None => return,
+        };
+        let feed = match self.feed_mode {
+            FeedMode::UnitsPerMinute => self.speed,
+            FeedMode::UnitsPerRev => self.speed.and_then(|f| self.spindle.rpm.map(|r| f * r)),
+            FeedMode::InverseTime => None,
+        };
+        if let (Some(feed), Some(max)) = (feed, entry.max_feed) {
+            if feed > max {
+                warnlog.warn(WarnType::ToolFeed(number, line_number));
+            }
+        }
+        if let (Some(rpm), Some(max)) = (self.spindle.rpm, entry.max_rpm) {
+            if rpm > max && self.spindle.dir != SpindleDir::Off {
+                warnlog.warn(WarnType::ToolRpm(number, line_number));
+            }
+        }
+    }
+
+    // F keeps no meaning from one feed mode to another
+    fn set_feed_mode(&mut self, mode: FeedMode) {
+        if mode != self.feed_mode {
+            self.feed_mode = mode;
+            self.speed = None;
+        }
+    }
+
+    // Tool 0 is the empty spindle
+    fn tool_entry(&self, tool: u8) -> Result<ToolEntry> {
+        match self.tools.get(&tool) {
+            Some(entry) => Ok(entry.clone()),
+            None if tool == 0 => Ok(ToolEntry::default()),
+            None => bail!(format!("Tool {} not found in tool table", tool)),
+        }
+    }
+
+    fn pos_tuple(&self) -> (f32, f32, f32) {
+        (self.pos.x.unwrap_or(0.), self.pos.y.unwrap_or(0.), self.pos.z.unwrap_or(0.))
     }
 
     fn convert_units(&self, mut dest: Coord, mut speed: Option<f32>) -> Result<(Coord, Option<f32>)> {
         match self.unit {
             Some(Unit::Inch) => {
                 dest.to_mm();
-                if let Some(u) = speed {
-                    speed = Some(u*25.4);
+                // Inverse time feeds have no length unit
+                match speed {
+                    Some(u) if self.feed_mode != FeedMode::InverseTime => speed = Some(u*25.4),
+                    _ => {},
                 }
             },
             Some(Unit::MM) => {},
@@ -123,16 +624,204 @@ impl Machine {
 
 
 
-fn g_tokenizer(i: u8) -> Result<GCode> {
-    match i {
-        0 => Ok(GCode::MT(MoveTypes::G0)),
-        1 => Ok(GCode::MT(MoveTypes::G1)),
-        2 => Ok(GCode::MT(MoveTypes::G2)),
-        3 => Ok(GCode::MT(MoveTypes::G3)),
-        20 => Ok(GCode::Flag(Flags::G20)),
-        21 => Ok(GCode::Flag(Flags::G21)),
-        90 => Ok(GCode::Flag(Flags::G90)),
-        91 => Ok(GCode::Flag(Flags::G91)),
-        _ => Ok(GCode::Dump),
+fn unit_scale(unit: &Option<Unit>) -> f32 {
+    match unit {
+        &Some(Unit::Inch) => 25.4,
+        _ => 1.,
+    }
+}
+
+fn comp_side(flag: &Flags) -> Side {
+    match flag {
+        &Flags::G42 | &Flags::G42_1 => Side::Right,
+        _ => Side::Left,
+    }
+}
+
+// Returns None for unknown decimal codes
+fn g_tokenizer(i: u8, sub: Option<u8>) -> Result<Option<GCode>> {
+    match (i, sub) {
+        (0, None) => Ok(Some(GCode::MT(MoveTypes::G0))),
+        (1, None) => Ok(Some(GCode::MT(MoveTypes::G1))),
+        (2, None) => Ok(Some(GCode::MT(MoveTypes::G2))),
+        (3, None) => Ok(Some(GCode::MT(MoveTypes::G3))),
+        (4, None) => Ok(Some(GCode::Dwell)),
+        (17, None) => Ok(Some(GCode::Flag(Flags::G17))),
+        (18, None) => Ok(Some(GCode::Flag(Flags::G18))),
+        (19, None) => Ok(Some(GCode::Flag(Flags::G19))),
+        (20, None) => Ok(Some(GCode::Flag(Flags::G20))),
+        (21, None) => Ok(Some(GCode::Flag(Flags::G21))),
+        (28, None) => Ok(Some(GCode::Flag(Flags::G28))),
+        (28, Some(1)) => Ok(Some(GCode::Flag(Flags::G28_1))),
+        (30, None) => Ok(Some(GCode::Flag(Flags::G30))),
+        (30, Some(1)) => Ok(Some(GCode::Flag(Flags::G30_1))),
+        (40, None) => Ok(Some(GCode::Flag(Flags::G40))),
+        (41, None) => Ok(Some(GCode::Flag(Flags::G41))),
+        (41, Some(1)) => Ok(Some(GCode::Flag(Flags::G41_1))),
+        (42, None) => Ok(Some(GCode::Flag(Flags::G42))),
+        (42, Some(1)) => Ok(Some(GCode::Flag(Flags::G42_1))),
+        (43, None) => Ok(Some(GCode::Flag(Flags::G43))),
+        (43, Some(1)) => Ok(Some(GCode::Flag(Flags::G43_1))),
+        (43, Some(2)) => Ok(Some(GCode::Flag(Flags::G43_2))),
+        (49, None) => Ok(Some(GCode::Flag(Flags::G49))),
+        (53, None) => Ok(Some(GCode::Flag(Flags::G53))),
+        (54, None) => Ok(Some(GCode::Flag(Flags::CoordSystem(0)))),
+        (55, None) => Ok(Some(GCode::Flag(Flags::CoordSystem(1)))),
+        (56, None) => Ok(Some(GCode::Flag(Flags::CoordSystem(2)))),
+        (57, None) => Ok(Some(GCode::Flag(Flags::CoordSystem(3)))),
+        (58, None) => Ok(Some(GCode::Flag(Flags::CoordSystem(4)))),
+        (59, None) => Ok(Some(GCode::Flag(Flags::CoordSystem(5)))),
+        (59, Some(1)) => Ok(Some(GCode::Flag(Flags::CoordSystem(6)))),
+        (59, Some(2)) => Ok(Some(GCode::Flag(Flags::CoordSystem(7)))),
+        (59, Some(3)) => Ok(Some(GCode::Flag(Flags::CoordSystem(8)))),
+        (61, None) => Ok(Some(GCode::Flag(Flags::G61))),
+        (61, Some(1)) => Ok(Some(GCode::Flag(Flags::G61_1))),
+        (64, None) => Ok(Some(GCode::Flag(Flags::G64))),
+        (73, None) => Ok(Some(GCode::Cycle(CycleType::G73))),
+        (80, None) => Ok(Some(GCode::Flag(Flags::G80))),
+        (81, None) => Ok(Some(GCode::Cycle(CycleType::G81))),
+        (82, None) => Ok(Some(GCode::Cycle(CycleType::G82))),
+        (83, None) => Ok(Some(GCode::Cycle(CycleType::G83))),
+        (84, None) => Ok(Some(GCode::Cycle(CycleType::G84))),
+        (85, None) => Ok(Some(GCode::Cycle(CycleType::G85))),
+        (86, None) => Ok(Some(GCode::Cycle(CycleType::G86))),
+        (87, None) => Ok(Some(GCode::Cycle(CycleType::G87))),
+        (88, None) => Ok(Some(GCode::Cycle(CycleType::G88))),
+        (89, None) => Ok(Some(GCode::Cycle(CycleType::G89))),
+        (90, None) => Ok(Some(GCode::Flag(Flags::G90))),
+        (91, None) => Ok(Some(GCode::Flag(Flags::G91))),
+        (92, None) => Ok(Some(GCode::Flag(Flags::G92))),
+        (92, Some(1)) => Ok(Some(GCode::Flag(Flags::G92_1))),
+        (92, Some(2)) => Ok(Some(GCode::Flag(Flags::G92_2))),
+        (92, Some(3)) => Ok(Some(GCode::Flag(Flags::G92_3))),
+        (93, None) => Ok(Some(GCode::Flag(Flags::G93))),
+        (94, None) => Ok(Some(GCode::Flag(Flags::G94))),
+        (95, None) => Ok(Some(GCode::Flag(Flags::G95))),
+        (98, None) => Ok(Some(GCode::Flag(Flags::G98))),
+        (99, None) => Ok(Some(GCode::Flag(Flags::G99))),
+        (_, None) => Ok(Some(GCode::Dump)),
+        (_, Some(s)) if KNOWN_DECIMAL_GCODES.contains(&(i, s)) => Ok(Some(GCode::Dump)),
+        (_, Some(_)) => Ok(None),
+    }
+}
+
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::lineparser::parse_line;
+    use crate::setup::{Cnc, Config};
+    use std::collections::BTreeMap;
+    use std::f32::consts::PI;
+
+    pub fn machine(cnc: Cnc) -> Machine {
+        Machine::new(Config {
+            cnc,
+            mcodes: BTreeMap::new(),
+            offsets: WorkOffsets::new(),
+            homes: HomePositions { g28: (0., 0., 0.), g30: (0., 0., 0.) },
+            tools: BTreeMap::new(),
+        })
+    }
+
+    pub fn mill() -> Cnc {
+        Cnc { speed_x: 1200., speed_y: 1200., speed_z: 600., ..Default::default() }
+    }
+
+    // Moves of a program, timed without look-ahead, and the total delay
+    pub fn run(machine: &mut Machine, program: &str, warnlog: &mut Warnlog)
+               -> Result<(Vec<Block>, f32)> {
+        let mut blocks = Vec::new();
+        let mut delay = 0.;
+        for (n, line) in program.lines().enumerate() {
+            let codes = match parse_line(line.to_string(), false)? {
+                Some(c) => c,
+                None => continue,
+            };
+            let (groups, _) = machine.line_depacker(codes, n + 1, warnlog)?;
+            for group in groups {
+                blocks.extend(group.get_stats(warnlog)?);
+                delay = delay + group.delays.iter().map(|d| d.duration).sum::<f32>();
+            }
+        }
+        Ok((blocks, delay))
+    }
+
+    fn distance(blocks: &[Block]) -> f32 {
+        blocks.iter().map(|b| b.distance).sum()
+    }
+
+    #[test]
+    fn p_word_after_an_arc_belongs_to_other_codes() {
+        let mut m = machine(mill());
+        let program = "G21 G90 G17 F600
+G2 X10 Y0 I5 J0
+G4 P0.5
+G64 P0.01
+G1 X20";
+        let (blocks, delay) = run(&mut m, program, &mut Warnlog::new()).unwrap();
+        assert_eq!(delay, 0.5);
+        assert!((distance(&blocks) - (5. * PI + 10.)).abs() < 1e-3);
+    }
+
+    #[test]
+    fn p_word_sets_arc_turns() {
+        let mut m = machine(mill());
+        let program = "G21 G90 G17 F600
+G2 X0 Y0 I5 J0 P2";
+        let (blocks, _) = run(&mut m, program, &mut Warnlog::new()).unwrap();
+        assert!((distance(&blocks) - 20. * PI).abs() < 1e-3);
+        assert!(run(&mut m, "G3 X10 I5 P0.5", &mut Warnlog::new()).is_err());
+    }
+
+    #[test]
+    fn axis_words_need_a_motion_mode() {
+        let mut m = machine(mill());
+        assert!(run(&mut m, "G21 G90 G17\nX5", &mut Warnlog::new()).is_err());
+        let mut m = machine(mill());
+        let program = "G21 G90 G17 F600
+G81 X5 Y0 Z-1 R1
+G80
+G92 X0";
+        assert!(run(&mut m, program, &mut Warnlog::new()).is_ok());
+        assert!(run(&mut m, "X10", &mut Warnlog::new()).is_err());
+    }
+
+    #[test]
+    fn undeclared_axis_words_are_dropped() {
+        let mut m = machine(mill());
+        let mut warnlog = Warnlog::new();
+        let program = "G21 G90 G17 F600
+G1 X10 V10
+G0 W5";
+        let (blocks, _) = run(&mut m, program, &mut warnlog).unwrap();
+        assert_eq!(distance(&blocks), 10.);
+        assert!(warnlog.logged_types.contains(&WarnType::UndeclaredAxis(Axis::V, 2)));
+        assert!(warnlog.logged_types.contains(&WarnType::UndeclaredAxis(Axis::W, 3)));
+    }
+
+    #[test]
+    fn inverse_time_is_shared_by_compensated_moves() {
+        let mut m = machine(mill());
+        let program = "G21 G90 G17 G93
+G42.1 D2
+G1 X10 Y0 F1
+G1 X10 Y10 F2
+G1 X0 Y10 F4
+G1 Z-1 F8";
+        let (blocks, _) = run(&mut m, program, &mut Warnlog::new()).unwrap();
+        let durations: Vec<f32> = blocks.iter().map(|b| b.duration).collect();
+        // Lines 3 and 4 end with a convex corner arc, done in the 1/F minute
+        // of their line. Line 5 is released with the Z move of line 6.
+        assert_eq!(durations.len(), 6);
+        let lines = [
+            durations[0] + durations[1],
+            durations[2] + durations[3],
+            durations[4],
+            durations[5],
+        ];
+        for (d, e) in lines.iter().zip([60., 30., 15., 7.5].iter()) {
+            assert!((d - e).abs() < 1e-3, "{:?}", durations);
+        }
     }
 }
@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use super::errors::*;
+use super::setup::{Cnc, Firmware};
+
+// Settings reported by Grbl 1.1's `$$` command
+const KNOWN_SETTINGS: [u16; 34] = [
+    0, 1, 2, 3, 4, 5, 6, 10, 11, 12, 13, 20, 21, 22, 23, 24, 25, 26, 27, 30, 31, 32, 100, 101, 102,
+    110, 111, 112, 120, 121, 122, 130, 131, 132,
+];
+
+fn parse_setting(line: &str) -> Result<(u16, f32)> {
+    if !line.starts_with('$') {
+        bail!("Setting does not start with '$'");
+    }
+    // Grbl 0.9 appends a description in parentheses
+    let line = match line.find('(') {
+        Some(i) => line[..i].trim(),
+        None => line,
+    };
+    let (number, value) = match line.find('=') {
+        Some(i) => (&line[1..i], &line[i + 1..]),
+        None => bail!("Expected $<number>=<value>"),
+    };
+    let number: u16 = number
+        .trim()
+        .parse()
+        .chain_err(|| format!("Invalid setting number: {}", number))?;
+    if !KNOWN_SETTINGS.contains(&number) {
+        bail!(format!("Unknown setting: ${}", number));
+    }
+    let value = value
+        .trim()
+        .parse()
+        .chain_err(|| format!("Invalid value for ${}: {}", number, value))?;
+    Ok((number, value))
+}
+
+pub fn parse_grbl_settings(path: &Path) -> Result<Cnc> {
+    let mut file = File::open(path).chain_err(|| "Error opening Grbl settings file")?;
+    let mut text = String::new();
+    file.read_to_string(&mut text)
+        .chain_err(|| "Error reading Grbl settings file")?;
+    cnc_from_settings(&text)
+}
+
+fn cnc_from_settings(text: &str) -> Result<Cnc> {
+    let mut settings = HashMap::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line == "ok" {
+            continue;
+        }
+        let (number, value) =
+            parse_setting(line).chain_err(|| format!("Malformed setting at line {}", n + 1))?;
+        if settings.insert(number, value).is_some() {
+            bail!(format!("Setting ${} defined twice", number));
+        }
+    }
+
+    let get = |number: u16| -> Result<f32> {
+        match settings.get(&number) {
+            Some(v) => Ok(*v),
+            None => bail!(format!("Missing setting ${}", number)),
+        }
+    };
+
+    Ok(Cnc {
+        speed_x: get(110)?,
+        speed_y: get(111)?,
+        speed_z: get(112)?,
+        accel_x: Some(get(120)?),
+        accel_y: Some(get(121)?),
+        accel_z: Some(get(122)?),
+        travel_x: settings.get(&130).cloned(),
+        travel_y: settings.get(&131).cloned(),
+        travel_z: settings.get(&132).cloned(),
+        junction_deviation: settings.get(&11).cloned(),
+        arc_tolerance: settings.get(&12).cloned(),
+        firmware: Some(Firmware::Grbl),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DUMP: &str = "$0=10
+$1=25
+$2=0
+$3=0
+$4=0
+$5=0
+$6=0
+$10=1
+$11=0.010
+$12=0.002
+$13=0
+$20=0
+$21=0
+$22=0
+$23=0
+$24=25.000
+$25=500.000
+$26=250
+$27=1.000
+$30=1000
+$31=0
+$32=0
+$100=250.000
+$101=250.000
+$102=250.000
+$110=500.000
+$111=500.000
+$112=500.000
+$120=10.000
+$121=10.000
+$122=10.000
+$130=200.000
+$131=200.000
+$132=200.000
+ok
+";
+
+    #[test]
+    fn settings_dump() {
+        let cnc = cnc_from_settings(DUMP).unwrap();
+        assert_eq!((cnc.speed_x, cnc.speed_y, cnc.speed_z), (500., 500., 500.));
+        assert_eq!(cnc.accel_x, Some(10.));
+        assert_eq!(cnc.travel_z, Some(200.));
+        assert_eq!(cnc.junction_deviation, Some(0.01));
+        assert_eq!(cnc.arc_tolerance, Some(0.002));
+        assert_eq!(cnc.firmware, Some(Firmware::Grbl));
+    }
+
+    #[test]
+    fn grbl_0_9_descriptions() {
+        assert_eq!(
+            parse_setting("$110=800.000 (x max rate, mm/min)").unwrap(),
+            (110, 800.)
+        );
+    }
+
+    #[test]
+    fn unknown_setting_number() {
+        assert!(parse_setting("$140=1").is_err());
+        let dump = format!("{}$999=1\n", DUMP);
+        assert!(cnc_from_settings(&dump).is_err());
+    }
+
+    #[test]
+    fn malformed_settings() {
+        assert!(parse_setting("110=500").is_err());
+        assert!(parse_setting("$110").is_err());
+        assert!(parse_setting("$110=fast").is_err());
+        assert!(cnc_from_settings("$110=500\n$110=600\n").is_err());
+        assert!(cnc_from_settings("$110=500\n").is_err());
+    }
+}
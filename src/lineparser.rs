@@ -1,51 +1,79 @@
 use super::errors::*;
+use super::objects_def::Axis;
 
 #[derive(Debug)]
 pub enum Codes {
-    G(u8),
-    M(u8),
+    G(u8, Option<u8>),
+    M(u8, Option<u8>),
     T(u8),
-    X(f32),
-    Y(f32),
-    Z(f32),
+    L(u32),
+    D(f32),
+    H(f32),
+    Axis(Axis, f32),
     I(f32),
     J(f32),
     K(f32),
     F(f32),
+    P(f32),
+    Q(f32),
+    R(f32),
+    S(f32),
 }
 
+// Splits a code number such as 59.1 into its number and subcode
+fn parse_code_number(acc: &str) -> Result<(u8, Option<u8>)> {
+    let (number, subcode) = match acc.find('.') {
+        Some(i) => (&acc[..i], Some(&acc[i + 1..])),
+        None => (acc, None),
+    };
+    let number = number.trim().parse()
+        .chain_err(|| "Error parsing code number")?;
+    let subcode = match subcode.map(|s| s.trim().trim_end_matches('0')) {
+        Some(s) if !s.is_empty() => Some(s.parse()
+            .chain_err(|| "Error parsing subcode")?),
+        _ => None,
+    };
+    Ok((number, subcode))
+}
+
+
 fn create_code(code: char, acc: &String) -> Result<Option<Codes>> {
     let acc = acc.trim();
+    if let Some(axis) = Axis::from_letter(code) {
+        let value = acc.parse()
+            .chain_err(|| "Error parsing value")?;
+        return Ok(Some(Codes::Axis(axis, value)));
+    }
     match code {
         'G' => {
-            let value = acc.parse()
+            let (value, subcode) = parse_code_number(acc)
                 .chain_err(|| "Error parsing value")?;
-            Ok(Some(Codes::G(value)))
+            Ok(Some(Codes::G(value, subcode)))
         },
         'M' => {
-            let value = acc.parse()
+            let (value, subcode) = parse_code_number(acc)
                 .chain_err(|| "Error parsing value")?;
-            Ok(Some(Codes::M(value)))
+            Ok(Some(Codes::M(value, subcode)))
         },
         'T' => {
             let value = acc.parse()
                 .chain_err(|| "Error parsing value")?;
             Ok(Some(Codes::T(value)))
         },
-        'X' => {
+        'L' => {
             let value = acc.parse()
                 .chain_err(|| "Error parsing value")?;
-            Ok(Some(Codes::X(value)))
+            Ok(Some(Codes::L(value)))
         },
-        'Y' => {
+        'D' => {
             let value = acc.parse()
                 .chain_err(|| "Error parsing value")?;
-            Ok(Some(Codes::Y(value)))
+            Ok(Some(Codes::D(value)))
         },
-        'Z' => {
+        'H' => {
             let value = acc.parse()
                 .chain_err(|| "Error parsing value")?;
-            Ok(Some(Codes::Z(value)))
+            Ok(Some(Codes::H(value)))
         },
         'I' => {
             let value = acc.parse()
@@ -67,6 +95,31 @@ fn create_code(code: char, acc: &String) -> Result<Option<Codes>> {
                 .chain_err(|| "Error parsing value")?;
             Ok(Some(Codes::F(value)))
         },
+        'P' => {
+            let value = acc.parse()
+                .chain_err(|| "Error parsing value")?;
+            Ok(Some(Codes::P(value)))
+        },
+        'Q' => {
+            let value = acc.parse()
+                .chain_err(|| "Error parsing value")?;
+            Ok(Some(Codes::Q(value)))
+        },
+        'R' => {
+            let value = acc.parse()
+                .chain_err(|| "Error parsing value")?;
+            Ok(Some(Codes::R(value)))
+        },
+        'S' => {
+            let value = acc.parse()
+                .chain_err(|| "Error parsing value")?;
+            Ok(Some(Codes::S(value)))
+        },
+        'N' => {
+            let _line_number: u32 = acc.parse()
+                .chain_err(|| "Error parsing line number")?;
+            Ok(None)
+        },
         _   => Ok(None),
     }
 }
@@ -86,27 +139,74 @@ fn process_letter(cl: &Option<char>, acc: &String, mut vec: Vec<Codes>) -> Resul
 }
 
 
-pub fn parse_line(line: String) -> Result<Option<Vec<Codes>>> {
+// star is the byte index of '*', column its position in characters
+fn check_checksum(line: &str, star: usize, column: usize) -> Result<()> {
+    let expected = line[..star].bytes().fold(0u8, |acc, b| acc ^ b);
+    let checksum = line[star + 1..].trim();
+    let value: u8 = checksum.parse()
+        .chain_err(|| format!("Invalid checksum at column {}: {}", column, checksum))?;
+    if value != expected {
+        bail!(format!("Checksum mismatch at column {}: expected {}, found {}",
+                      column, expected, value));
+    }
+    Ok(())
+}
+
+
+pub fn parse_line(line: String, block_delete: bool) -> Result<Option<Vec<Codes>>> {
     let mut line_codes: Vec<Codes> = Vec::new();
     let mut acc = String::new();
     let mut current_letter = None;
+    let mut comment_start = None;
 
-    if line.starts_with("(") {
+    let trimmed = line.trim_start();
+    if trimmed.starts_with('%') {
         return Ok(None)
     }
+    let mut skip = line.len() - trimmed.len();
+    if trimmed.starts_with('/') {
+        if block_delete {
+            return Ok(None)
+        }
+        skip = skip + 1;
+    }
 
-    for item in line.chars() {
-        if item.is_alphabetic() {
-            line_codes = process_letter(&current_letter, &acc, line_codes)
-                .chain_err(|| "Error processing letter")?;
-            current_letter = Some(item.to_ascii_uppercase());
-            acc.clear();
+    // Columns count characters, comments may hold non-ASCII ones
+    let chars = line.char_indices().enumerate().skip_while(|&(_, (i, _))| i < skip);
+    for (count, (index, item)) in chars {
+        let column = count + 1;
+        if let Some(start) = comment_start {
+            match item {
+                ')' => comment_start = None,
+                '(' => bail!(format!("Nested comment at column {} (opened at column {})", column, start)),
+                _ => {},
+            }
+            continue;
         }
-        else {
-            acc.push(item);
+
+        match item {
+            '(' => comment_start = Some(column),
+            ')' => bail!(format!("Unbalanced ')' at column {}", column)),
+            ';' => break,
+            '*' => {
+                check_checksum(&line, index, column)
+                    .chain_err(|| "Error checking checksum")?;
+                break;
+            },
+            _ if item.is_alphabetic() => {
+                line_codes = process_letter(&current_letter, &acc, line_codes)
+                    .chain_err(|| "Error processing letter")?;
+                current_letter = Some(item.to_ascii_uppercase());
+                acc.clear();
+            },
+            _ => acc.push(item),
         }
     }
 
+    if let Some(start) = comment_start {
+        bail!(format!("Unterminated comment opened at column {}", start));
+    }
+
     line_codes = process_letter(&current_letter, &acc, line_codes)
         .chain_err(|| "Error processing letter")?;
 
@@ -117,3 +217,79 @@ pub fn parse_line(line: String) -> Result<Option<Vec<Codes>>> {
         Ok(Some(line_codes))
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codes(line: &str) -> String {
+        let codes = parse_line(line.to_string(), false).unwrap().unwrap_or_default();
+        format!("{:?}", codes)
+    }
+
+    #[test]
+    fn inline_comments() {
+        assert_eq!(codes("G1 (feed move) X2 (to the edge)"), "[G(1, None), Axis(X, 2.0)]");
+        assert_eq!(codes("(only a comment)"), "[]");
+        assert_eq!(codes("G0 X1 (semicolons; are allowed in comments)"),
+                   "[G(0, None), Axis(X, 1.0)]");
+    }
+
+    #[test]
+    fn semicolon_comments() {
+        assert_eq!(codes("G0 X1 ; rapid (move)"), "[G(0, None), Axis(X, 1.0)]");
+        assert_eq!(codes("; G1 X2"), "[]");
+    }
+
+    #[test]
+    fn malformed_comments() {
+        for line in ["G1 (outer (inner)) X2", "G1 X2) Y3", "G1 X2 (unterminated"].iter() {
+            assert!(parse_line(line.to_string(), false).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn tape_markers_and_line_numbers() {
+        assert!(parse_line("%".to_string(), false).unwrap().is_none());
+        assert!(parse_line("  % program start".to_string(), false).unwrap().is_none());
+        assert_eq!(codes("N120 G0 Z5"), "[G(0, None), Axis(Z, 5.0)]");
+        assert!(parse_line("N1.5 G0".to_string(), false).is_err());
+    }
+
+    #[test]
+    fn block_delete() {
+        assert!(parse_line("/G0 X1".to_string(), true).unwrap().is_none());
+        assert!(parse_line(" / G0 X1".to_string(), true).unwrap().is_none());
+        assert_eq!(codes("/G0 X1"), "[G(0, None), Axis(X, 1.0)]");
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(codes("N10 G1 X2*83"), "[G(1, None), Axis(X, 2.0)]");
+        assert!(parse_line("N10 G1 X2*84".to_string(), false).is_err());
+        assert!(parse_line("N10 G1 X2*x".to_string(), false).is_err());
+        assert!(check_checksum("N10 G1 X2*83", 9, 10).is_ok());
+    }
+
+    #[test]
+    fn columns_count_characters() {
+        let error = |line: &str| -> Vec<String> {
+            let e = parse_line(line.to_string(), false).unwrap_err();
+            e.iter().map(|m| m.to_string()).collect()
+        };
+        assert_eq!(error("(Ø6 endmill) X2)")[0], "Unbalanced ')' at column 16");
+        assert_eq!(error("(45°) (a (b)")[0], "Nested comment at column 10 (opened at column 7)");
+        assert!(error("(Ø6) G1*x").contains(&"Invalid checksum at column 8: x".to_string()));
+    }
+
+    #[test]
+    fn decimal_codes() {
+        assert_eq!(codes("G59.1"), "[G(59, Some(1))]");
+        assert_eq!(codes("g64.0 p0.01"), "[G(64, None), P(0.01)]");
+        assert_eq!(codes("G38.2 Z-5"), "[G(38, Some(2)), Axis(Z, -5.0)]");
+        assert_eq!(codes("M62.1"), "[M(62, Some(1))]");
+        assert_eq!(codes("G01"), "[G(1, None)]");
+        assert!(parse_line("G59.x".to_string(), false).is_err());
+    }
+}
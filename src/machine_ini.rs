@@ -0,0 +1,300 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use super::errors::*;
+use super::setup::{AxisSettings, Cnc, Firmware, LinearUnits};
+
+struct Ini {
+    sections: HashMap<String, HashMap<String, String>>,
+}
+impl Ini {
+    fn parse(text: &str) -> Result<Ini> {
+        let mut sections: HashMap<String, HashMap<String, String>> = HashMap::new();
+        let mut current = None;
+
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if line.starts_with('[') {
+                if !line.ends_with(']') {
+                    bail!(format!("Malformed section header at line {}", n + 1));
+                }
+                let name = line[1..line.len() - 1].trim().to_string();
+                sections.entry(name.clone()).or_default();
+                current = Some(name);
+                continue;
+            }
+            let (key, value) = match line.find('=') {
+                Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+                None => bail!(format!("Expected KEY = VALUE at line {}", n + 1)),
+            };
+            let section = match current {
+                Some(ref s) => s,
+                None => bail!(format!("Key outside of any section at line {}", n + 1)),
+            };
+            // Like LinuxCNC, the first occurrence of a key wins
+            sections
+                .get_mut(section)
+                .unwrap()
+                .entry(key.to_string())
+                .or_insert_with(|| value.to_string());
+        }
+        Ok(Ini { sections })
+    }
+
+    fn get(&self, section: &str, key: &str) -> Option<&str> {
+        match self.sections.get(section) {
+            Some(s) => s.get(key).map(|v| v.as_str()),
+            None => None,
+        }
+    }
+
+    fn get_f32(&self, section: &str, key: &str) -> Result<Option<f32>> {
+        match self.get(section, key) {
+            Some(v) => {
+                let value = v
+                    .parse()
+                    .chain_err(|| format!("Invalid value for [{}] {}: {}", section, key, v))?;
+                Ok(Some(value))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Finds the section holding the limits of an axis: [AXIS_X] first, then
+    // the joint driving it, then pre-2.8 numbered [AXIS_n] sections.
+    fn axis_section(&self, letter: char, index: usize) -> Option<String> {
+        let name = format!("AXIS_{}", letter);
+        if self.has_limits(&name) {
+            return Some(name);
+        }
+
+        let coordinates: Vec<char> = self
+            .get("TRAJ", "COORDINATES")
+            .unwrap_or("")
+            .chars()
+            .filter(|c| c.is_alphabetic())
+            .map(|c| c.to_ascii_uppercase())
+            .collect();
+        if let Some(joint) = coordinates.iter().position(|&c| c == letter) {
+            let name = format!("JOINT_{}", joint);
+            if self.has_limits(&name) {
+                return Some(name);
+            }
+        }
+
+        let name = format!("AXIS_{}", index);
+        if self.has_limits(&name) {
+            return Some(name);
+        }
+        None
+    }
+
+    fn has_limits(&self, section: &str) -> bool {
+        self.get(section, "MAX_VELOCITY").is_some()
+    }
+}
+
+// Builds the machine model from a LinuxCNC INI file. Velocities are given
+// in machine units per second there, accelerations in units per second².
+pub fn parse_machine_ini(path: &Path) -> Result<Cnc> {
+    let mut file = File::open(path).chain_err(|| "Error opening machine INI file")?;
+    let mut text = String::new();
+    file.read_to_string(&mut text)
+        .chain_err(|| "Error reading machine INI file")?;
+
+    let dir = path.parent().unwrap_or(Path::new(""));
+    cnc_from_ini(&text, dir)
+}
+
+// Paths in the INI file are relative to its directory
+fn cnc_from_ini(text: &str, dir: &Path) -> Result<Cnc> {
+    let ini = Ini::parse(text).chain_err(|| "Error parsing machine INI file")?;
+
+    let (units, scale) = match ini.get("TRAJ", "LINEAR_UNITS") {
+        Some("mm") | Some("metric") | None => (LinearUnits::Mm, 1.),
+        Some("inch") | Some("in") | Some("imperial") => (LinearUnits::Inch, 25.4),
+        Some(u) => bail!(format!("Unsupported [TRAJ] LINEAR_UNITS: {}", u)),
+    };
+
+    let mut speeds = [0.; 3];
+    let mut accels = [None; 3];
+    for (index, letter) in ['X', 'Y', 'Z'].iter().enumerate() {
+        let section = match ini.axis_section(*letter, index) {
+            Some(s) => s,
+            None => bail!(format!("No MAX_VELOCITY found for axis {}", letter)),
+        };
+        let velocity = ini
+            .get_f32(&section, "MAX_VELOCITY")
+            .chain_err(|| "Error reading axis velocity")?
+            .unwrap_or(0.);
+        speeds[index] = velocity * scale * 60.;
+        accels[index] = ini
+            .get_f32(&section, "MAX_ACCELERATION")
+            .chain_err(|| "Error reading axis acceleration")?
+            .map(|a| a * scale);
+    }
+
+    // Other axes are optional. Rotary ones are in degrees.
+    let mut axes = BTreeMap::new();
+    for (index, letter) in ['A', 'B', 'C', 'U', 'V', 'W'].iter().enumerate() {
+        let section = match ini.axis_section(*letter, index + 3) {
+            Some(s) => s,
+            None => continue,
+        };
+        let rotary = index < 3;
+        let axis_scale = if rotary { 1. } else { scale };
+        let velocity = ini
+            .get_f32(&section, "MAX_VELOCITY")
+            .chain_err(|| "Error reading axis velocity")?
+            .unwrap_or(0.);
+        let accel = ini
+            .get_f32(&section, "MAX_ACCELERATION")
+            .chain_err(|| "Error reading axis acceleration")?
+            .map(|a| a * axis_scale);
+        let rollover = match rotary {
+            true => ini
+                .get_f32(&section, "WRAPPED_ROTARY")
+                .chain_err(|| "Error reading wrapped rotary setting")?
+                .map(|w| w != 0.),
+            false => None,
+        };
+        axes.insert(
+            letter.to_string(),
+            AxisSettings {
+                speed: velocity * axis_scale * 60.,
+                accel,
+                radius: None,
+                rollover,
+            },
+        );
+    }
+
+    let tool_table = ini
+        .get("EMCIO", "TOOL_TABLE")
+        .map(|t| dir.join(PathBuf::from(t)));
+    let var_file = ini
+        .get("RS274NGC", "PARAMETER_FILE")
+        .map(|f| dir.join(PathBuf::from(f)));
+
+    Ok(Cnc {
+        speed_x: speeds[0],
+        speed_y: speeds[1],
+        speed_z: speeds[2],
+        accel_x: accels[0],
+        accel_y: accels[1],
+        accel_z: accels[2],
+        axes: if axes.is_empty() { None } else { Some(axes) },
+        firmware: Some(Firmware::LinuxCnc),
+        tool_table,
+        var_file,
+        linear_units: Some(units),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MILL_INI: &str = "
+# LinuxCNC 2.8 style
+[TRAJ]
+COORDINATES = X Y Z A
+LINEAR_UNITS = inch
+
+[RS274NGC]
+PARAMETER_FILE = linuxcnc.var
+
+[EMCIO]
+TOOL_TABLE = tool.tbl
+
+[AXIS_X]
+MAX_VELOCITY = 2
+MAX_ACCELERATION = 10
+
+[JOINT_1]
+MAX_VELOCITY = 1.5
+MAX_ACCELERATION = 8
+
+; Z only has a joint too
+[JOINT_2]
+MAX_VELOCITY = 0.5
+MAX_VELOCITY = 9
+MAX_ACCELERATION = 4
+
+[AXIS_A]
+MAX_VELOCITY = 90
+MAX_ACCELERATION = 360
+WRAPPED_ROTARY = 1
+";
+
+    #[test]
+    fn axis_and_joint_sections_in_mm_per_min() {
+        let cnc = cnc_from_ini(MILL_INI, Path::new("/cfg")).unwrap();
+        assert_eq!(cnc.speed_x, 2. * 25.4 * 60.);
+        assert_eq!(cnc.speed_y, 1.5 * 25.4 * 60.);
+        // The first occurrence of a key wins
+        assert_eq!(cnc.speed_z, 0.5 * 25.4 * 60.);
+        assert_eq!(cnc.accel_x, Some(10. * 25.4));
+        assert_eq!(cnc.accel_z, Some(4. * 25.4));
+        assert_eq!(cnc.firmware, Some(Firmware::LinuxCnc));
+        assert_eq!(cnc.tool_table, Some(PathBuf::from("/cfg/tool.tbl")));
+        assert_eq!(cnc.var_file, Some(PathBuf::from("/cfg/linuxcnc.var")));
+        assert_eq!(cnc.linear_units, Some(LinearUnits::Inch));
+    }
+
+    #[test]
+    fn wrapped_rotary_axis_stays_in_degrees() {
+        let cnc = cnc_from_ini(MILL_INI, Path::new("")).unwrap();
+        let axes = cnc.axes.unwrap();
+        let a = &axes["A"];
+        assert_eq!(a.speed, 90. * 60.);
+        assert_eq!(a.accel, Some(360.));
+        assert_eq!(a.rollover, Some(true));
+        assert!(!axes.contains_key("B"));
+    }
+
+    #[test]
+    fn numbered_axis_sections() {
+        let ini = "
+[AXIS_0]
+MAX_VELOCITY = 10
+[AXIS_1]
+MAX_VELOCITY = 10
+[AXIS_2]
+MAX_VELOCITY = 5
+";
+        let cnc = cnc_from_ini(ini, Path::new("")).unwrap();
+        assert_eq!((cnc.speed_x, cnc.speed_z), (600., 300.));
+        assert_eq!(cnc.accel_x, None);
+        assert!(cnc.axes.is_none());
+    }
+
+    #[test]
+    fn short_inch_units() {
+        let ini = "[TRAJ]\nLINEAR_UNITS = in\n[AXIS_X]\nMAX_VELOCITY = 1\n\
+                   [AXIS_Y]\nMAX_VELOCITY = 1\n[AXIS_Z]\nMAX_VELOCITY = 1\n";
+        let cnc = cnc_from_ini(ini, Path::new("")).unwrap();
+        assert_eq!(cnc.linear_units, Some(LinearUnits::Inch));
+        assert_eq!(cnc.speed_z, 25.4 * 60.);
+    }
+
+    #[test]
+    fn missing_axis_and_bad_units() {
+        assert!(cnc_from_ini("[AXIS_X]\nMAX_VELOCITY = 1\n", Path::new("")).is_err());
+        let ini = "[TRAJ]\nLINEAR_UNITS = furlong\n";
+        assert!(cnc_from_ini(ini, Path::new("")).is_err());
+    }
+
+    #[test]
+    fn malformed_lines() {
+        assert!(Ini::parse("[AXIS_X\n").is_err());
+        assert!(Ini::parse("[AXIS_X]\nMAX_VELOCITY\n").is_err());
+        assert!(Ini::parse("MAX_VELOCITY = 1\n").is_err());
+    }
+}
@@ -13,23 +13,31 @@ extern crate read_lines;
 
 mod errors;
 mod setup;
+mod machine_ini;
+mod grbl_settings;
 pub mod lineparser;
 pub mod objects_def;
 mod gcode_lexer;
+mod canned_cycles;
+mod cutter_comp;
 mod calculator;
 mod math_tools;
 mod output;
+mod parameters;
+mod planner;
+mod tool_table;
 
 use std::fs::File;
 use log::Level;
 use chrono::prelude::*;
 
-use setup::{Cnc,get_config};
+use setup::get_config;
 use read_lines::read_line::LineReader;
 use lineparser::parse_line;
 use errors::*;
-use objects_def::{Machine,Status,Tool};
+use objects_def::{Block,Delay,Machine,Status,Tool};
 use output::*;
+use planner::Planner;
 
 
 fn main() {
@@ -62,13 +70,16 @@ fn main() {
 fn run() -> Result<()> {
     let start = Utc::now();
 
-    let (input, config, list_result, logconf) = get_config()
+    let (input, config, list_result, block_delete, logconf) = get_config()
         .chain_err(|| "Could not get configuration")?;
     logger_init(logconf)
         .chain_err(|| "Error initializing logger(s)")?;
 
     if log_enabled!(Level::Info) {
         info!("Analizing {}", input.to_string_lossy());
+        if let Some(ref t) = config.cnc.tool_table {
+            info!("Machine tool table: {}", t.to_string_lossy());
+        }
     }
 
     let file = File::open(input)
@@ -77,7 +88,8 @@ fn run() -> Result<()> {
     let line_reader = LineReader::new(file)
         .chain_err(|| "Error creating LineReader")?;
 
-    let mut machine = Machine::new(config.cnc);
+    let mut planner = Planner::new(&config.cnc);
+    let mut machine = Machine::new(config);
     let mut warnlog = Warnlog::new();
     let mut tools_list: Vec<Tool> = Vec::new();
 
@@ -88,40 +100,45 @@ fn run() -> Result<()> {
         let line = line
             .chain_err(|| "Error reading line")?;
 
-        if log_enabled!(Level::Info) {num_of_line = num_of_line + 1;}
+        num_of_line = num_of_line + 1;
 
         if log_enabled!(Level::Trace) {trace!("line: {}",line);}
 
-        let parsed = match parse_line(line)
-            .chain_err(|| "Error parsing line")? {
+        let parsed = match parse_line(line, block_delete)
+            .chain_err(|| format!("Error parsing line {}", num_of_line))? {
             Some(p) => {
                 debug!("Parsed line: {:?}", p);
                 p
             },
             None => continue,
         };
-        let (modgroup, &tool_number) = machine.line_depacker(parsed)
+        let (modgroups, &tool_number) = machine.line_depacker(parsed, num_of_line, &mut warnlog)
             .chain_err(|| "Error depacking line")?;
-        if log_enabled!(Level::Trace) {trace!("Modgroup: {:?}", modgroup);}
 
-        let (t,d) = modgroup.get_stats(&mut warnlog)
-            .chain_err(|| "Error computing stats in modal group")?;
+        for modgroup in modgroups {
+            if log_enabled!(Level::Trace) {trace!("Modgroup: {:?}", modgroup);}
 
-        if tool_number != tool.tool_number {
-            debug!("Changing tool number to {:?}", tool_number);
-            tools_list.push(tool.clone());
-            tool.reset(tool_number);
-        }
+            let blocks = modgroup.get_stats(&mut warnlog)
+                .chain_err(|| "Error computing stats in modal group")?;
 
-        tool.duration = tool.duration + t;
-        tool.distance = tool.distance + d;
+            add_delays(modgroup.delays, tool_number, &mut planner, &mut tool, &mut tools_list);
+            for mut block in blocks {
+                block.tool_number = tool_number;
+                add_blocks(planner.push(block), &mut tool, &mut tools_list);
+            }
+        }
 
         if let Status::EOP = machine.status {
+            add_blocks(planner.flush(), &mut tool, &mut tools_list);
             tools_list.push(tool);
             warnlog.print_messages();
 
-            let messages = get_tool_messages(tools_list, list_result);
+            let messages = get_tool_messages(tools_list, &machine.tools, list_result);
             println!("{}", messages);
+            let messages = get_auxiliary_messages(&machine.mcodes, &machine.mcode_counts);
+            if !messages.is_empty() {
+                println!("{}", messages);
+            }
 
             if log_enabled!(Level::Info) {
                 let prog_duration: f64 = Utc::now().signed_duration_since(start)
@@ -138,3 +155,40 @@ fn run() -> Result<()> {
     bail!("Reached EOF without End Of Programm");
 }
 
+
+fn switch_tool(tool_number: Option<u8>, tool: &mut Tool, tools_list: &mut Vec<Tool>) {
+    if tool_number != tool.tool_number {
+        debug!("Changing tool number to {:?}", tool_number);
+        tools_list.push(tool.clone());
+        tool.reset(tool_number);
+    }
+}
+
+
+fn add_blocks(blocks: Vec<Block>, tool: &mut Tool, tools_list: &mut Vec<Tool>) {
+    for block in blocks {
+        switch_tool(block.tool_number, tool, tools_list);
+
+        if log_enabled!(Level::Trace) {trace!("Planned block: {:?}", block);}
+
+        tool.duration = tool.duration + block.duration;
+        tool.distance = tool.distance + block.distance;
+    }
+}
+
+
+// Delays stop the axes, so the planned moves must end at null speed first
+fn add_delays(delays: Vec<Delay>, tool_number: Option<u8>, planner: &mut Planner,
+              tool: &mut Tool, tools_list: &mut Vec<Tool>) {
+    if delays.is_empty() {
+        return;
+    }
+    add_blocks(planner.flush(), tool, tools_list);
+    switch_tool(tool_number, tool, tools_list);
+
+    for delay in delays.iter() {
+        if log_enabled!(Level::Debug) {debug!("Delay: {:?}", delay);}
+        tool.add_delay(delay);
+    }
+}
+
@@ -1,8 +1,15 @@
+use std::f32::consts::PI;
+
 use super::errors::*;
 use crate::objects_def::*;
 
+// Allowed error on R arcs radius, as in LinuxCNC
+const TOLERANCE_MM: f32 = 0.005;
+const TOLERANCE_INCH: f32 = 0.0005;
+
 impl Coord {
-    pub fn to_rad_vec(&self, dest: &Coord, reference: &Referential) -> Result<(Coord, Coord, f32)> {
+    // Destinations are absolute, missing axes keeping their position
+    fn to_points(&self, dest: &Coord) -> Result<((f32, f32, f32), (f32, f32, f32))> {
         let &dest = dest;
 
         let x0 = match self.x {
@@ -18,50 +25,111 @@ impl Coord {
             None => bail!("Current Z position not set"),
         };
 
-        let (x1, y1, mut z1, i, j, k) = match reference {
-            &Referential::Absolute => {
-                let x1 = dest.x.unwrap_or(x0);
-                let y1 = dest.y.unwrap_or(y0);
-                let z1 = dest.z.unwrap_or(z0);
-                let i = dest.i.unwrap_or(0.);
-                let j = dest.j.unwrap_or(0.);
-                let k = dest.k.unwrap_or(0.);
-                (x1, y1, z1, i, j, k)
-            }
-            &Referential::Increment => {
-                let x1 = x0 + dest.x.unwrap_or(0.);
-                let y1 = y0 + dest.y.unwrap_or(0.);
-                let z1 = z0 + dest.z.unwrap_or(0.);
-                let i = dest.i.unwrap_or(0.);
-                let j = dest.j.unwrap_or(0.);
-                let k = dest.k.unwrap_or(0.);
-                (x1, y1, z1, i, j, k)
-            }
-        };
+        let end = (
+            dest.x.unwrap_or(x0),
+            dest.y.unwrap_or(y0),
+            dest.z.unwrap_or(z0),
+        );
+        Ok(((x0, y0, z0), end))
+    }
 
-        let mut dz = 0.;
-        if k == 0. {
-            dz = z1 - z0;
-            z1 = z0;
-        }
+    // Returns the center-to-start and center-to-end vectors in the arc plane
+    // coordinates, and the helical travel along the perpendicular axis.
+    pub fn to_rad_vec(&self, dest: &Coord, plane: &Plane) -> Result<(Coord, Coord, f32)> {
+        let (start, end) = self
+            .to_points(dest)
+            .chain_err(|| "Error getting arc end points")?;
+        let offsets = (
+            dest.i.unwrap_or(0.),
+            dest.j.unwrap_or(0.),
+            dest.k.unwrap_or(0.),
+        );
+
+        let (u0, v0, w0) = plane.project(start);
+        let (u1, v1, w1) = plane.project(end);
+        let (ou, ov, _) = plane.project(offsets);
 
         let cp = Coord {
-            x: Some(-i),
-            y: Some(-j),
-            z: Some(-k),
+            x: Some(-ou),
+            y: Some(-ov),
+            z: Some(0.),
+            extra: [None; 6],
             i: None,
             j: None,
             k: None,
+            r: None,
         };
         let cd = Coord {
-            x: Some(x1 - x0 - i),
-            y: Some(y1 - y0 - j),
-            z: Some(z1 - z0 - k),
+            x: Some(u1 - u0 - ou),
+            y: Some(v1 - v0 - ov),
+            z: Some(0.),
+            extra: [None; 6],
             i: None,
             j: None,
             k: None,
+            r: None,
         };
-        Ok((cp, cd, dz))
+        Ok((cp, cd, w1 - w0))
+    }
+
+    // Replaces the R word of a radius format arc by the equivalent center
+    // offsets. A positive radius gives the short arc, a negative one the
+    // long arc.
+    pub fn radius_to_offsets(
+        &self,
+        dest: &Coord,
+        plane: &Plane,
+        clockwise: bool,
+        unit: &Option<Unit>,
+    ) -> Result<Coord> {
+        let radius = match dest.r {
+            Some(r) => r,
+            None => return Ok(*dest),
+        };
+        let (start, end) = self
+            .to_points(dest)
+            .chain_err(|| "Error getting arc end points")?;
+        let (u0, v0, _) = plane.project(start);
+        let (u1, v1, _) = plane.project(end);
+
+        let tolerance = match unit {
+            &Some(Unit::Inch) => TOLERANCE_INCH * 25.4,
+            _ => TOLERANCE_MM,
+        };
+
+        let (mid_u, mid_v) = ((u0 + u1) / 2., (v0 + v1) / 2.);
+        let mut half_length = ((mid_u - u0).powi(2) + (mid_v - v0).powi(2)).sqrt();
+        if half_length == 0. {
+            bail!("Start point is the same as end point of radius format arc");
+        }
+        if half_length - radius.abs() > tolerance {
+            bail!(format!(
+                "Arc radius too small to reach end point: R{} for a {} chord",
+                radius.abs(),
+                2. * half_length
+            ));
+        }
+        if half_length > radius.abs() {
+            half_length = radius.abs();
+        }
+
+        let chord_angle = (v1 - v0).atan2(u1 - u0);
+        let normal_angle = if clockwise == (radius > 0.) {
+            chord_angle - PI / 2.
+        } else {
+            chord_angle + PI / 2.
+        };
+        let offset = (radius.powi(2) - half_length.powi(2)).sqrt();
+        let center_u = mid_u + offset * normal_angle.cos();
+        let center_v = mid_v + offset * normal_angle.sin();
+
+        let (i, j, k) = plane.unproject((center_u - u0, center_v - v0, 0.));
+        let mut dest = *dest;
+        dest.i = Some(i);
+        dest.j = Some(j);
+        dest.k = Some(k);
+        dest.r = None;
+        Ok(dest)
     }
 
     pub fn norm(&self) -> f32 {
@@ -105,3 +173,203 @@ impl Coord {
         scalar_prod
     }
 }
+
+impl Plane {
+    // Maps (x, y, z) to (first plane axis, second plane axis, perpendicular
+    // axis), ordered so that arcs keep their direction of rotation.
+    pub fn project<T>(&self, v: (T, T, T)) -> (T, T, T) {
+        let (x, y, z) = v;
+        match self {
+            &Plane::XY => (x, y, z),
+            &Plane::XZ => (z, x, y),
+            &Plane::YZ => (y, z, x),
+        }
+    }
+
+    pub fn unproject<T>(&self, v: (T, T, T)) -> (T, T, T) {
+        let (u, v, w) = v;
+        match self {
+            &Plane::XY => (u, v, w),
+            &Plane::XZ => (v, w, u),
+            &Plane::YZ => (w, u, v),
+        }
+    }
+}
+
+// Velocities in mm/s, acceleration in mm/s², duration in s.
+// Moves too short to reach v_cruise get a triangular profile instead.
+pub fn trapezoid_duration(dist: f32, v_entry: f32, v_cruise: f32, v_exit: f32, accel: f32) -> f32 {
+    if dist <= 0. {
+        return 0.;
+    }
+
+    let d_accel = (v_cruise.powi(2) - v_entry.powi(2)) / (2. * accel);
+    let d_decel = (v_cruise.powi(2) - v_exit.powi(2)) / (2. * accel);
+
+    if d_accel + d_decel <= dist {
+        let t_accel = (v_cruise - v_entry) / accel;
+        let t_decel = (v_cruise - v_exit) / accel;
+        let t_cruise = (dist - d_accel - d_decel) / v_cruise;
+        return t_accel + t_cruise + t_decel;
+    }
+
+    let v_peak = ((2. * accel * dist + v_entry.powi(2) + v_exit.powi(2)) / 2.).sqrt();
+    if v_peak < v_entry.max(v_exit) {
+        // Entry and exit velocities are not reachable from one another on
+        // this distance: fall back on a constant acceleration between them.
+        return 2. * dist / (v_entry + v_exit);
+    }
+
+    (v_peak - v_entry) / accel + (v_peak - v_exit) / accel
+}
+
+// Largest value along the move direction such that no axis exceeds its own
+// limit, e.g. the path speed from per-axis max speeds.
+pub fn path_limit(deltas: (f32, f32, f32), limits: (f32, f32, f32)) -> f32 {
+    let (dx, dy, dz) = deltas;
+    let dist = (dx.powi(2) + dy.powi(2) + dz.powi(2)).sqrt();
+    let mut limit = ::std::f32::INFINITY;
+    for &(d, l) in [(dx, limits.0), (dy, limits.1), (dz, limits.2)].iter() {
+        if d != 0. {
+            limit = limit.min(l * dist / d.abs());
+        }
+    }
+    if limit.is_infinite() {
+        limit = limits.0.min(limits.1).min(limits.2);
+    }
+    limit
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-4, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn trapezoid_reaches_cruise_speed() {
+        // 50 to accelerate, 100 at 10/s, 50 to decelerate
+        assert_close(trapezoid_duration(200., 0., 10., 0., 1.), 30.);
+    }
+
+    #[test]
+    fn trapezoid_with_entry_and_exit_speeds() {
+        // 37.5 to accelerate from 5, 100 at 10/s, 48 to decelerate to 2
+        assert_close(trapezoid_duration(185.5, 5., 10., 2., 1.), 5. + 10. + 8.);
+    }
+
+    #[test]
+    fn triangle_below_cruise_speed() {
+        // Peaks at sqrt(50) halfway
+        let v_peak = 50f32.sqrt();
+        assert_close(trapezoid_duration(50., 0., 10., 0., 1.), 2. * v_peak);
+    }
+
+    #[test]
+    fn unreachable_exit_speed_falls_back_on_constant_accel() {
+        assert_close(trapezoid_duration(1., 10., 20., 0., 1.), 0.2);
+    }
+
+    #[test]
+    fn empty_move_takes_no_time() {
+        assert_eq!(trapezoid_duration(0., 0., 10., 0., 1.), 0.);
+    }
+
+    #[test]
+    fn path_limit_is_set_by_the_slowest_axis() {
+        // Y travels 4/5 of the path: 100 on Y gives 125 along the path
+        assert_close(path_limit((3., 4., 0.), (100., 100., 100.)), 125.);
+        assert_close(path_limit((3., 4., 0.), (100., 200., 100.)), 5. / 3. * 100.);
+        assert_close(path_limit((0., 0., -2.), (100., 100., 40.)), 40.);
+    }
+
+    #[test]
+    fn path_limit_without_move() {
+        assert_close(path_limit((0., 0., 0.), (100., 50., 80.)), 50.);
+    }
+
+    fn origin() -> Coord {
+        let mut c = Coord::new();
+        c.x = Some(0.);
+        c.y = Some(0.);
+        c.z = Some(0.);
+        c
+    }
+
+    fn r_arc(x: f32, z: f32, r: f32) -> Coord {
+        let mut c = Coord::new();
+        c.x = Some(x);
+        c.z = Some(z);
+        c.r = Some(r);
+        c
+    }
+
+    fn offsets(
+        dest: &Coord,
+        plane: &Plane,
+        clockwise: bool,
+        unit: Option<Unit>,
+    ) -> (f32, f32, f32) {
+        let d = origin()
+            .radius_to_offsets(dest, plane, clockwise, &unit)
+            .unwrap();
+        assert!(d.r.is_none());
+        (d.i.unwrap(), d.j.unwrap(), d.k.unwrap())
+    }
+
+    #[test]
+    fn positive_radius_gives_the_short_arc() {
+        let h = 75f32.sqrt();
+        // Clockwise over the top: the center is below the chord
+        let (i, j, _) = offsets(&r_arc(10., 0., 10.), &Plane::XY, true, None);
+        assert_close(i, 5.);
+        assert_close(j, -h);
+        let (i, j, _) = offsets(&r_arc(10., 0., 10.), &Plane::XY, false, None);
+        assert_close(i, 5.);
+        assert_close(j, h);
+    }
+
+    #[test]
+    fn negative_radius_gives_the_long_arc() {
+        let h = 75f32.sqrt();
+        let (i, j, _) = offsets(&r_arc(10., 0., -10.), &Plane::XY, true, None);
+        assert_close(i, 5.);
+        assert_close(j, h);
+        let (i, j, _) = offsets(&r_arc(10., 0., -10.), &Plane::XY, false, None);
+        assert_close(i, 5.);
+        assert_close(j, -h);
+    }
+
+    #[test]
+    fn radius_offsets_in_other_planes() {
+        // G18: the arc is drawn in ZX, Z being the first plane axis
+        let (i, j, k) = offsets(&r_arc(0., 10., 10.), &Plane::XZ, true, None);
+        assert_close(i, -75f32.sqrt());
+        assert_close(j, 0.);
+        assert_close(k, 5.);
+    }
+
+    #[test]
+    fn radius_just_under_half_the_chord() {
+        // Within TOLERANCE_MM, the center is taken at the chord middle
+        let (i, j, _) = offsets(&r_arc(10., 0., 5. - 0.004), &Plane::XY, true, None);
+        assert_close(i, 5.);
+        assert_close(j, 0.);
+        let too_small = r_arc(10., 0., 5. - 0.01);
+        assert!(origin()
+            .radius_to_offsets(&too_small, &Plane::XY, true, &None)
+            .is_err());
+        // Inch programs get TOLERANCE_INCH
+        let (i, _, _) = offsets(&too_small, &Plane::XY, true, Some(Unit::Inch));
+        assert_close(i, 5.);
+    }
+
+    #[test]
+    fn radius_arc_without_travel() {
+        assert!(origin()
+            .radius_to_offsets(&r_arc(0., 0., 5.), &Plane::XY, true, &None)
+            .is_err());
+    }
+}
@@ -1,54 +1,267 @@
-use super::Cnc;
+use super::cutter_comp::CutterComp;
+use super::setup::{AxisSettings, Config, DwellUnit, Firmware, MCode};
+use super::tool_table::ToolEntry;
+use std::collections::BTreeMap;
+
+const GRBL_ARC_TOLERANCE: f32 = 0.002;
 
 #[derive(Debug)]
 pub struct Machine {
     pub move_type: Option<GCode>,
     pub pos: Coord,
+    pub center: Coord,
+    pub offsets: WorkOffsets,
+    pub homes: HomePositions,
     pub max_speed: (f32, f32, f32),
+    pub max_accel: Option<(f32, f32, f32)>,
+    pub arc_tolerance: Option<f32>,
+    pub max_travel: (Option<f32>, Option<f32>, Option<f32>),
+    pub extra_axes: [Option<ExtraAxis>; 6],
     pub speed: Option<f32>,
+    pub feed_mode: FeedMode,
     pub unit: Option<Unit>,
     pub reference: Option<Referential>,
+    pub path_mode: PathMode,
+    pub plane: Plane,
+    pub retract_mode: RetractMode,
+    pub cycle: CannedCycle,
+    pub dwell_unit: DwellUnit,
+    pub pause_time: f32,
+    pub tool_change_time: f32,
+    pub spindle: Spindle,
+    pub mcodes: BTreeMap<u8, MCode>,
+    pub mcode_counts: BTreeMap<u8, u32>,
+    pub tools: BTreeMap<u8, ToolEntry>,
+    pub comp: CutterComp,
     pub tool_number: Option<u8>,
     pub status: Status,
 }
 impl Machine {
-    pub fn new(config: Cnc) -> Machine {
+    pub fn new(config: Config) -> Machine {
+        let Config {
+            cnc: config,
+            mcodes,
+            offsets,
+            homes,
+            tools,
+        } = config;
+        let mut origin = Coord::new();
+        origin.x = Some(0.0);
+        origin.y = Some(0.0);
+        origin.z = Some(0.0);
+        origin.extra = [Some(0.0); 6];
         Machine {
             move_type: None,
-            pos: Coord {
-                x: Some(0.0),
-                y: Some(0.0),
-                z: Some(0.0),
-                i: None,
-                j: None,
-                k: None,
-            },
+            pos: origin,
+            center: origin,
+            offsets,
+            homes,
             max_speed: (config.speed_x, config.speed_y, config.speed_z),
+            max_accel: match (config.accel_x, config.accel_y, config.accel_z) {
+                (Some(x), Some(y), Some(z)) => Some((x, y, z)),
+                _ => None,
+            },
+            arc_tolerance: match config.get_firmware() {
+                Firmware::Grbl => Some(config.arc_tolerance.unwrap_or(GRBL_ARC_TOLERANCE)),
+                _ => config.arc_tolerance,
+            },
+            max_travel: (config.travel_x, config.travel_y, config.travel_z),
+            extra_axes: ExtraAxis::from_settings(config.axes.as_ref()),
             speed: None,
+            feed_mode: FeedMode::UnitsPerMinute,
             unit: None,
             reference: None,
+            path_mode: PathMode::Blending(None, None),
+            plane: Plane::XY,
+            retract_mode: RetractMode::OldZ,
+            cycle: CannedCycle::new(),
+            dwell_unit: config.get_dwell_unit(),
+            pause_time: config.pause_time.unwrap_or(0.),
+            tool_change_time: config.get_tool_change_time(),
+            spindle: Spindle {
+                dir: SpindleDir::Off,
+                rpm: None,
+                up_time: config.spindle_up_time.unwrap_or(0.),
+                down_time: config.spindle_down_time.unwrap_or(0.),
+                max_rpm: config.spindle_max_rpm,
+            },
+            mcodes,
+            mcode_counts: BTreeMap::new(),
+            tools,
+            comp: CutterComp::new(),
             tool_number: None,
             status: Status::Continue,
         }
     }
 }
 
+// Work coordinate systems G54 to G59.3, G92 offsets and tool length offset,
+// in mm. Program coordinates plus the current offset give machine
+// coordinates.
+#[derive(Debug)]
+pub struct WorkOffsets {
+    pub systems: [(f32, f32, f32); 9],
+    pub active: usize,
+    pub g92: (f32, f32, f32),
+    pub g92_saved: (f32, f32, f32),
+    pub tool_length: f32,
+}
+impl WorkOffsets {
+    pub fn new() -> WorkOffsets {
+        WorkOffsets {
+            systems: [(0., 0., 0.); 9],
+            active: 0,
+            g92: (0., 0., 0.),
+            g92_saved: (0., 0., 0.),
+            tool_length: 0.,
+        }
+    }
+
+    pub fn current(&self) -> Coord {
+        let (x, y, z) = self.systems[self.active];
+        let (gx, gy, gz) = self.g92;
+        let mut offset = Coord::new();
+        offset.x = Some(x + gx);
+        offset.y = Some(y + gy);
+        offset.z = Some(z + gz + self.tool_length);
+        offset
+    }
+}
+
+// Axis letters of G-code. X, Y and Z are always there, the others are
+// declared per machine.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Axis {
+    X,
+    Y,
+    Z,
+    A,
+    B,
+    C,
+    U,
+    V,
+    W,
+}
+impl Axis {
+    pub fn from_letter(letter: char) -> Option<Axis> {
+        match letter {
+            'X' => Some(Axis::X),
+            'Y' => Some(Axis::Y),
+            'Z' => Some(Axis::Z),
+            'A' => Some(Axis::A),
+            'B' => Some(Axis::B),
+            'C' => Some(Axis::C),
+            'U' => Some(Axis::U),
+            'V' => Some(Axis::V),
+            'W' => Some(Axis::W),
+            _ => None,
+        }
+    }
+
+    // Index in Coord::extra, None for X, Y and Z
+    pub fn extra_index(&self) -> Option<usize> {
+        match self {
+            &Axis::X | &Axis::Y | &Axis::Z => None,
+            &Axis::A => Some(0),
+            &Axis::B => Some(1),
+            &Axis::C => Some(2),
+            &Axis::U => Some(3),
+            &Axis::V => Some(4),
+            &Axis::W => Some(5),
+        }
+    }
+
+    pub fn is_rotary(&self) -> bool {
+        match self {
+            &Axis::A | &Axis::B | &Axis::C => true,
+            _ => false,
+        }
+    }
+}
+
+// Axes A, B, C, U, V and W in Coord::extra order
+pub const EXTRA_AXES: [Axis; 6] = [Axis::A, Axis::B, Axis::C, Axis::U, Axis::V, Axis::W];
+
+// Declared axis besides X, Y and Z. Speeds are in mm/min, or deg/min for
+// the rotary axes A, B and C, accelerations per second.
+// With a radius (mm), rotations count in the path length as the arc
+// travelled at that radius. Without, feeds only apply to the linear axes,
+// or are in deg/min for rotary only moves. Rollover axes go the shortest
+// way to their absolute position, modulo 360°.
+#[derive(Debug, Copy, Clone)]
+pub struct ExtraAxis {
+    pub max_speed: f32,
+    pub max_accel: Option<f32>,
+    pub radius: Option<f32>,
+    pub rollover: bool,
+}
+impl ExtraAxis {
+    // Settings are keyed by axis letter, checked when loading the config
+    fn from_settings(settings: Option<&BTreeMap<String, AxisSettings>>) -> [Option<ExtraAxis>; 6] {
+        let mut axes = [None; 6];
+        for (key, s) in settings.into_iter().flat_map(|m| m.iter()) {
+            let index = key
+                .to_uppercase()
+                .chars()
+                .next()
+                .and_then(Axis::from_letter)
+                .and_then(|a| a.extra_index());
+            if let Some(i) = index {
+                axes[i] = Some(ExtraAxis {
+                    max_speed: s.speed,
+                    max_accel: s.accel,
+                    radius: s.radius,
+                    rollover: s.rollover.unwrap_or(false),
+                });
+            }
+        }
+        axes
+    }
+}
+
+// Positions reached by G28 and G30, in machine coordinates
+#[derive(Debug)]
+pub struct HomePositions {
+    pub g28: (f32, f32, f32),
+    pub g30: (f32, f32, f32),
+}
+
 #[derive(Debug)]
 pub struct ModalGroup<'a> {
-    pub move_type: &'a Option<GCode>,
+    pub move_type: Option<MoveTypes>,
     pub origin: Coord,
     pub dest: Option<Coord>,
-    pub speed: &'a Option<f32>,
+    pub speed: Option<f32>,
+    pub feed_mode: FeedMode,
     pub max_speed: &'a (f32, f32, f32),
+    pub max_accel: &'a Option<(f32, f32, f32)>,
+    pub arc_tolerance: &'a Option<f32>,
+    pub max_travel: &'a (Option<f32>, Option<f32>, Option<f32>),
+    pub extra_axes: &'a [Option<ExtraAxis>; 6],
     pub unit: &'a Option<Unit>,
-    pub reference: &'a Option<Referential>,
+    pub path_mode: &'a PathMode,
+    pub plane: &'a Plane,
+    pub spindle: &'a Spindle,
+    pub turns: u32,
+    // Length over which an inverse time F applies, when the programmed
+    // move is split into several
+    pub feed_length: Option<f32>,
+    pub line_number: usize,
+    pub delays: Vec<Delay>,
 }
 
+// Duration includes dwells; operator pauses are kept apart since they
+// depend on whoever stands at the machine.
 #[derive(Debug, Copy)]
 pub struct Tool {
     pub tool_number: Option<u8>,
     pub duration: f32,
     pub distance: f32,
+    pub dwell: f32,
+    pub tool_change: f32,
+    pub spindle: f32,
+    pub auxiliary: f32,
+    pub pause: f32,
 }
 impl Tool {
     pub fn new(tool_number: Option<u8>) -> Tool {
@@ -56,6 +269,11 @@ impl Tool {
             tool_number,
             duration: 0.,
             distance: 0.,
+            dwell: 0.,
+            tool_change: 0.,
+            spindle: 0.,
+            auxiliary: 0.,
+            pause: 0.,
         }
     }
 
@@ -63,6 +281,33 @@ impl Tool {
         self.tool_number = tool;
         self.duration = 0.;
         self.distance = 0.;
+        self.dwell = 0.;
+        self.tool_change = 0.;
+        self.spindle = 0.;
+        self.auxiliary = 0.;
+        self.pause = 0.;
+    }
+
+    pub fn add_delay(&mut self, delay: &Delay) {
+        match delay.kind {
+            DelayKind::Dwell => {
+                self.dwell = self.dwell + delay.duration;
+                self.duration = self.duration + delay.duration;
+            }
+            DelayKind::ToolChange => {
+                self.tool_change = self.tool_change + delay.duration;
+                self.duration = self.duration + delay.duration;
+            }
+            DelayKind::Spindle => {
+                self.spindle = self.spindle + delay.duration;
+                self.duration = self.duration + delay.duration;
+            }
+            DelayKind::Auxiliary(_) => {
+                self.auxiliary = self.auxiliary + delay.duration;
+                self.duration = self.duration + delay.duration;
+            }
+            DelayKind::Pause => self.pause = self.pause + delay.duration,
+        }
     }
 }
 impl Clone for Tool {
@@ -71,14 +316,107 @@ impl Clone for Tool {
     }
 }
 
+#[derive(Debug, Copy)]
+pub struct Block {
+    pub tool_number: Option<u8>,
+    pub distance: f32,
+    pub duration: f32,
+    pub speed: f32,
+    pub accel: f32,
+    pub entry_dir: (f32, f32, f32),
+    pub exit_dir: (f32, f32, f32),
+    pub path_mode: PathMode,
+}
+impl Clone for Block {
+    fn clone(&self) -> Block {
+        *self
+    }
+}
+
+// Time spent with the axes stopped, in seconds
+#[derive(Debug, Copy)]
+pub struct Delay {
+    pub kind: DelayKind,
+    pub duration: f32,
+}
+impl Clone for Delay {
+    fn clone(&self) -> Delay {
+        *self
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DelayKind {
+    Dwell,
+    ToolChange,
+    Spindle,
+    Auxiliary(u8),
+    Pause,
+}
+
+// Spin-up and spin-down times are given for the full speed range: without a
+// maximum RPM, every start or stop takes the full time.
+#[derive(Debug)]
+pub struct Spindle {
+    pub dir: SpindleDir,
+    pub rpm: Option<f32>,
+    pub up_time: f32,
+    pub down_time: f32,
+    pub max_rpm: Option<f32>,
+}
+impl Spindle {
+    // Updates the spindle state and returns the time spent changing speed
+    pub fn update(&mut self, dir: Option<SpindleDir>, rpm: Option<f32>) -> f32 {
+        let from = self.signed_speed();
+        if let Some(d) = dir {
+            self.dir = d;
+        }
+        if let Some(r) = rpm {
+            self.rpm = Some(r);
+        }
+        let to = self.signed_speed();
+
+        if from * to < 0. {
+            // Reversal: the spindle stops before starting the other way
+            self.down_time * from.abs() + self.up_time * to.abs()
+        } else if to.abs() > from.abs() {
+            self.up_time * (to.abs() - from.abs())
+        } else {
+            self.down_time * (from.abs() - to.abs())
+        }
+    }
+
+    // Share of the full speed range, negative when counterclockwise
+    fn signed_speed(&self) -> f32 {
+        let speed = match (self.rpm, self.max_rpm) {
+            (Some(rpm), Some(max)) => (rpm / max).min(1.),
+            _ => 1.,
+        };
+        match self.dir {
+            SpindleDir::Off => 0.,
+            SpindleDir::CW => speed,
+            SpindleDir::CCW => -speed,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SpindleDir {
+    Off,
+    CW,
+    CCW,
+}
+
 #[derive(Debug, Copy)]
 pub struct Coord {
     pub x: Option<f32>,
     pub y: Option<f32>,
     pub z: Option<f32>,
+    pub extra: [Option<f32>; 6],
     pub i: Option<f32>,
     pub j: Option<f32>,
     pub k: Option<f32>,
+    pub r: Option<f32>,
 }
 impl Coord {
     pub fn new() -> Coord {
@@ -86,9 +424,11 @@ impl Coord {
             x: None,
             y: None,
             z: None,
+            extra: [None; 6],
             i: None,
             j: None,
             k: None,
+            r: None,
         }
     }
 
@@ -102,6 +442,11 @@ impl Coord {
         if let Some(_) = updater.z {
             self.z = updater.z;
         }
+        for (value, update) in self.extra.iter_mut().zip(updater.extra.iter()) {
+            if let Some(_) = *update {
+                *value = *update;
+            }
+        }
     }
 
     pub fn add(&mut self, adder: &Coord) {
@@ -120,6 +465,37 @@ impl Coord {
                 self.z = Some(z + az);
             }
         }
+        for (value, add) in self.extra.iter_mut().zip(adder.extra.iter()) {
+            if let (&mut Some(v), &Some(a)) = (&mut *value, add) {
+                *value = Some(v + a);
+            }
+        }
+    }
+
+    pub fn get(&self, axis: Axis) -> Option<f32> {
+        match axis {
+            Axis::X => self.x,
+            Axis::Y => self.y,
+            Axis::Z => self.z,
+            _ => self.extra[axis.extra_index().unwrap_or(0)],
+        }
+    }
+
+    pub fn set(&mut self, axis: Axis, value: Option<f32>) {
+        match axis {
+            Axis::X => self.x = value,
+            Axis::Y => self.y = value,
+            Axis::Z => self.z = value,
+            _ => self.extra[axis.extra_index().unwrap_or(0)] = value,
+        }
+    }
+
+    // True when a position is given on any axis
+    pub fn has_axes(&self) -> bool {
+        self.x.is_some()
+            || self.y.is_some()
+            || self.z.is_some()
+            || self.extra.iter().any(|e| e.is_some())
     }
 
     pub fn is_empty(&self) -> bool {
@@ -130,15 +506,25 @@ impl Coord {
             i: None,
             j: None,
             k: None,
+            r: None,
+            ..
         } = self
         {
-            return true;
+            return !self.has_axes();
         } else {
             return false;
         }
     }
 
+    // Rotary axes stay in degrees
     pub fn to_mm(&mut self) {
+        for (axis, value) in EXTRA_AXES.iter().zip(self.extra.iter_mut()) {
+            if let Some(u) = *value {
+                if !axis.is_rotary() {
+                    *value = Some(u * 25.4);
+                }
+            }
+        }
         if let Some(u) = self.x {
             self.x = Some(u * 25.4);
         }
@@ -157,6 +543,9 @@ impl Coord {
         if let Some(u) = self.k {
             self.k = Some(u * 25.4);
         }
+        if let Some(u) = self.r {
+            self.r = Some(u * 25.4);
+        }
     }
 }
 impl Clone for Coord {
@@ -168,11 +557,13 @@ impl Clone for Coord {
 #[derive(Debug)]
 pub enum GCode {
     MT(MoveTypes),
+    Cycle(CycleType),
     Flag(Flags),
+    Dwell,
     Dump,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 pub enum MoveTypes {
     G0,
     G1,
@@ -180,12 +571,92 @@ pub enum MoveTypes {
     G3,
 }
 
+#[derive(Debug, Copy, Clone)]
+pub enum CycleType {
+    G73,
+    G81,
+    G82,
+    G83,
+    G84,
+    G85,
+    G86,
+    G87,
+    G88,
+    G89,
+}
+
+// Sticky words of a series of canned cycles, as given in the program, and
+// the level the series started from.
+#[derive(Debug)]
+pub struct CannedCycle {
+    pub initial_level: Option<f32>,
+    pub r: Option<f32>,
+    pub bottom: Option<f32>,
+    pub peck: Option<f32>,
+    pub dwell: Option<f32>,
+}
+impl CannedCycle {
+    pub fn new() -> CannedCycle {
+        CannedCycle {
+            initial_level: None,
+            r: None,
+            bottom: None,
+            peck: None,
+            dwell: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RetractMode {
+    OldZ,
+    RPlane,
+}
+
 #[derive(Debug)]
 pub enum Flags {
+    CoordSystem(usize),
+    G17,
+    G18,
+    G19,
     G20,
     G21,
+    G28,
+    G28_1,
+    G30,
+    G30_1,
+    G40,
+    G41,
+    G41_1,
+    G42,
+    G42_1,
+    G43,
+    G43_1,
+    G43_2,
+    G49,
+    G53,
+    G61,
+    G61_1,
+    G64,
+    G80,
     G90,
     G91,
+    G92,
+    G92_1,
+    G92_2,
+    G92_3,
+    G93,
+    G94,
+    G95,
+    G98,
+    G99,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum Plane {
+    XY,
+    XZ,
+    YZ,
 }
 
 #[derive(Debug)]
@@ -200,6 +671,33 @@ pub enum Referential {
     Increment,
 }
 
+// Blending carries the G64 P (path tolerance) and Q (naive CAM tolerance)
+// values, in mm.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PathMode {
+    ExactStop,
+    ExactPath,
+    Blending(Option<f32>, Option<f32>),
+}
+
+// Meaning of F: mm/min, 1/min for the whole move (G93) or mm per spindle
+// revolution (G95)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FeedMode {
+    UnitsPerMinute,
+    InverseTime,
+    UnitsPerRev,
+}
+
+// Feed of a programmed line. Cutter compensation releases moves on the
+// lines after theirs, which are still timed with it.
+#[derive(Debug, Copy, Clone)]
+pub struct LineFeed {
+    pub speed: Option<f32>,
+    pub mode: FeedMode,
+    pub line_number: usize,
+}
+
 #[derive(Debug)]
 pub enum Status {
     Continue,
@@ -3,7 +3,10 @@ use std::fs::File;
 use std::path::PathBuf;
 
 use super::errors::*;
-use crate::objects_def::Tool;
+use crate::objects_def::{Axis, Tool};
+use crate::setup::MCode;
+use crate::tool_table::ToolEntry;
+use std::collections::BTreeMap;
 
 pub fn logger_init(logconf: (u8, Option<PathBuf>)) -> Result<()> {
     let (verbose, logfile) = logconf;
@@ -38,11 +41,18 @@ pub fn logger_init(logconf: (u8, Option<PathBuf>)) -> Result<()> {
 #[derive(PartialEq)]
 pub enum WarnType {
     TooFast,
+    BeyondTravel,
+    UnknownCode(String, usize),
+    UndeclaredAxis(Axis, usize),
+    DegenerateArc(usize),
+    SpindleOff(usize),
+    ToolFeed(u8, usize),
+    ToolRpm(u8, usize),
 }
 
 pub struct Warnlog {
     messages: Vec<String>,
-    logged_types: Vec<WarnType>,
+    pub logged_types: Vec<WarnType>,
 }
 
 impl Warnlog {
@@ -63,6 +73,76 @@ impl Warnlog {
                 );
                 self.store_messages(message, t);
             }
+            WarnType::BeyondTravel => {
+                let message = String::from(
+                    "A move is longer than the machine's travel on one axis: \
+                                            the program will not fit on this machine.",
+                );
+                self.store_messages(message, t);
+            }
+            WarnType::UnknownCode(ref code, line) => {
+                let message = format!("Unknown code {} ignored at line {}", code, line);
+                self.store_messages(message, t);
+            }
+            WarnType::UndeclaredAxis(axis, line) => {
+                // Reported once per axis
+                let logged = self.logged_types.iter().any(|l| match l {
+                    &WarnType::UndeclaredAxis(a, _) => a == axis,
+                    _ => false,
+                });
+                if !logged {
+                    let message = format!(
+                        "Axis {:?} is not declared for this machine: ignored from line {}",
+                        axis, line
+                    );
+                    self.store_messages(message, t);
+                }
+            }
+            WarnType::SpindleOff(line) => {
+                // Only the first occurrence is reported
+                let logged = self.logged_types.iter().any(|l| match l {
+                    &WarnType::SpindleOff(_) => true,
+                    _ => false,
+                });
+                if !logged {
+                    let message = format!("Cutting move with the spindle off at line {}", line);
+                    self.store_messages(message, t);
+                }
+            }
+            WarnType::ToolFeed(tool, line) => {
+                // Reported once per tool
+                let logged = self.logged_types.iter().any(|l| match l {
+                    &WarnType::ToolFeed(t, _) => t == tool,
+                    _ => false,
+                });
+                if !logged {
+                    let message = format!(
+                        "Feed higher than the max feed of tool {} at line {}",
+                        tool, line
+                    );
+                    self.store_messages(message, t);
+                }
+            }
+            WarnType::ToolRpm(tool, line) => {
+                let logged = self.logged_types.iter().any(|l| match l {
+                    &WarnType::ToolRpm(t, _) => t == tool,
+                    _ => false,
+                });
+                if !logged {
+                    let message = format!(
+                        "Spindle speed higher than the max RPM of tool {} at line {}",
+                        tool, line
+                    );
+                    self.store_messages(message, t);
+                }
+            }
+            WarnType::DegenerateArc(line) => {
+                let message = format!(
+                    "Arc with a null radius at line {}: computed as a straight move",
+                    line
+                );
+                self.store_messages(message, t);
+            }
         }
     }
 
@@ -80,27 +160,126 @@ impl Warnlog {
     }
 }
 
-pub fn get_tool_messages(tools_list: Vec<Tool>, list_result: bool) -> String {
+pub fn get_tool_messages(
+    tools_list: Vec<Tool>,
+    library: &BTreeMap<u8, ToolEntry>,
+    list_result: bool,
+) -> String {
     let mut message = String::new();
     let mut total_dist = 0.;
     let mut total_dura = 0.;
+    let mut total_pause = 0.;
 
     for item in tools_list.iter() {
         if list_result {
             match item.tool_number {
-                Some(u) => message.push_str(format!("Tool {}:\n", u).as_str()),
-                None if item.distance != 0. => message.push_str("No tool:\n"),
+                Some(u) => message.push_str(format!("{}:\n", tool_label(u, library)).as_str()),
+                None if item.distance != 0. || item.duration != 0. || item.pause != 0. => {
+                    message.push_str("No tool:\n")
+                }
                 None => continue,
             }
             message.push_str(format!("  Duration: {}\n", time_format(item.duration)).as_str());
-            message.push_str(format!("  Distance: {:.*}mm\n\n", 1, item.distance).as_str());
+            if item.dwell != 0. {
+                message.push_str(format!("    Dwell: {}\n", time_format(item.dwell)).as_str());
+            }
+            if item.tool_change != 0. {
+                message.push_str(
+                    format!("    Tool change: {}\n", time_format(item.tool_change)).as_str(),
+                );
+            }
+            if item.auxiliary != 0. {
+                message
+                    .push_str(format!("    Auxiliary: {}\n", time_format(item.auxiliary)).as_str());
+            }
+            if item.spindle != 0. {
+                message.push_str(format!("    Spindle: {}\n", time_format(item.spindle)).as_str());
+            }
+            message.push_str(format!("  Distance: {:.*}mm\n", 1, item.distance).as_str());
+            if item.pause != 0. {
+                message
+                    .push_str(format!("  Operator pauses: {}\n", time_format(item.pause)).as_str());
+            }
+            message.push_str("\n");
         }
         total_dist = total_dist + item.distance;
         total_dura = total_dura + item.duration;
+        total_pause = total_pause + item.pause;
     }
 
     message.push_str(format!("Total duration: {}\n", time_format(total_dura)).as_str());
     message.push_str(format!("Total distance: {:.*}mm\n", 1, total_dura).as_str());
+    if total_pause != 0. {
+        message.push_str(format!("Operator pauses: {}\n", time_format(total_pause)).as_str());
+        message.push_str(
+            format!(
+                "Total with pauses: {}\n",
+                time_format(total_dura + total_pause)
+            )
+            .as_str(),
+        );
+    }
+    message
+}
+
+// Reads like "T3 — 2mm 2-flute endmill" for tools of the library
+fn tool_label(number: u8, library: &BTreeMap<u8, ToolEntry>) -> String {
+    let entry = match library.get(&number) {
+        Some(e) => e,
+        None => return format!("Tool {}", number),
+    };
+    let mut words = Vec::new();
+    if entry.diameter > 0. {
+        words.push(format!("{}mm", entry.diameter));
+    }
+    if let Some(f) = entry.flutes {
+        words.push(format!("{}-flute", f));
+    }
+    if let Some(ref d) = entry.description {
+        words.push(d.clone());
+    }
+    if words.is_empty() {
+        return format!("T{}", number);
+    }
+    format!("T{} \u{2014} {}", number, words.join(" "))
+}
+
+pub fn get_auxiliary_messages(mcodes: &BTreeMap<u8, MCode>, counts: &BTreeMap<u8, u32>) -> String {
+    let mut message = String::new();
+    let mut total = 0.;
+
+    for (number, count) in counts.iter() {
+        let mcode = &mcodes[number];
+        let time = mcode.delay * *count as f32;
+        let label = match mcode.label {
+            Some(ref l) => format!("M{} {}", number, l),
+            None => format!("M{}", number),
+        };
+        message.push_str(format!("  {}: {} ({}x)\n", label, time_format(time), count).as_str());
+        total = total + time;
+    }
+
+    if message.is_empty() {
+        return message;
+    }
+    format!("Auxiliary time: {}\n{}", time_format(total), message)
+}
+
+pub fn get_machine_list(names: Vec<String>, default: Option<String>) -> String {
+    let mut message = String::new();
+    if names.is_empty() {
+        message.push_str("No [machines.<name>] profile in config file");
+        return message;
+    }
+
+    message.push_str("Available machines:\n");
+    for name in names.iter() {
+        if Some(name) == default.as_ref() {
+            message.push_str(format!("  {} (default)\n", name).as_str());
+        } else {
+            message.push_str(format!("  {}\n", name).as_str());
+        }
+    }
     message
 }
 
@@ -123,7 +302,7 @@ fn time_format(mut t: f32) -> String {
 
     if t > 1. {
         out.push_str(format!("{:.*}s", 0, t).as_str());
-    } else {
+    } else if out.is_empty() {
         out.push_str("< 1s");
     }
 
@@ -0,0 +1,168 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use super::errors::*;
+use super::setup::Cnc;
+use crate::objects_def::{HomePositions, WorkOffsets};
+
+const WCS_NAMES: [&str; 9] = [
+    "G54", "G55", "G56", "G57", "G58", "G59", "G59.1", "G59.2", "G59.3",
+];
+// LinuxCNC numbered parameters
+const PARAM_G28: u16 = 5161;
+const PARAM_G30: u16 = 5181;
+const PARAM_G92_ENABLED: u16 = 5210;
+const PARAM_G92: u16 = 5211;
+const PARAM_ACTIVE_WCS: u16 = 5220;
+const PARAM_G54: u16 = 5221;
+const PARAM_WCS_STEP: u16 = 20;
+
+// Reads a LinuxCNC parameter file, made of `<number> <value>` lines
+pub fn parse_var_file(path: &Path) -> Result<BTreeMap<u16, f32>> {
+    let mut file = File::open(path).chain_err(|| "Error opening parameter file")?;
+    let mut text = String::new();
+    file.read_to_string(&mut text)
+        .chain_err(|| "Error reading parameter file")?;
+
+    let mut params = BTreeMap::new();
+    for (n, line) in text.lines().enumerate() {
+        let mut fields = line.split_whitespace();
+        let (number, value) = match (fields.next(), fields.next(), fields.next()) {
+            (None, _, _) => continue,
+            (Some(number), Some(value), None) => (number, value),
+            _ => bail!(format!("Expected <number> <value> at line {}", n + 1)),
+        };
+        let number: u16 = number
+            .parse()
+            .chain_err(|| format!("Invalid parameter number at line {}", n + 1))?;
+        let value: f32 = value
+            .parse()
+            .chain_err(|| format!("Invalid parameter value at line {}", n + 1))?;
+        params.insert(number, value);
+    }
+    Ok(params)
+}
+
+fn get_xyz(params: &BTreeMap<u16, f32>, first: u16, scale: f32) -> (f32, f32, f32) {
+    let get = |n: u16| params.get(&n).cloned().unwrap_or(0.) * scale;
+    (get(first), get(first + 1), get(first + 2))
+}
+
+// Offsets and G28/G30 positions are read from the parameter file first, the
+// config file entries overriding them. The parameter file is in the machine
+// linear units, the config file in mm.
+pub fn get_parameters(cnc: &Cnc) -> Result<(WorkOffsets, HomePositions)> {
+    let mut offsets = WorkOffsets::new();
+    let mut homes = HomePositions {
+        g28: (0., 0., 0.),
+        g30: (0., 0., 0.),
+    };
+
+    if let Some(ref path) = cnc.var_file {
+        let params = parse_var_file(path)
+            .chain_err(|| format!("Error parsing {}", path.to_string_lossy()))?;
+        let scale = cnc.get_linear_scale();
+        for (index, system) in offsets.systems.iter_mut().enumerate() {
+            *system = get_xyz(&params, PARAM_G54 + PARAM_WCS_STEP * index as u16, scale);
+        }
+        if let Some(&active) = params.get(&PARAM_ACTIVE_WCS) {
+            if !(1. ..=9.).contains(&active) || active.fract() != 0. {
+                bail!(format!("Invalid active coordinate system: {}", active));
+            }
+            offsets.active = active as usize - 1;
+        }
+        offsets.g92_saved = get_xyz(&params, PARAM_G92, scale);
+        if params.get(&PARAM_G92_ENABLED).cloned().unwrap_or(0.) != 0. {
+            offsets.g92 = offsets.g92_saved;
+        }
+        homes.g28 = get_xyz(&params, PARAM_G28, scale);
+        homes.g30 = get_xyz(&params, PARAM_G30, scale);
+    }
+
+    if let Some(ref table) = cnc.work_offsets {
+        for (name, &offset) in table.iter() {
+            match WCS_NAMES.iter().position(|n| n == name) {
+                Some(index) => offsets.systems[index] = offset,
+                None => bail!(format!("Unknown coordinate system: {}", name)),
+            }
+        }
+    }
+
+    if let Some(position) = cnc.g28_position {
+        homes.g28 = position;
+    }
+    if let Some(position) = cnc.g30_position {
+        homes.g30 = position;
+    }
+
+    Ok((offsets, homes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::setup::LinearUnits;
+    use std::fs;
+
+    const VAR_FILE: &str = "5161 1.0
+5162 2.0
+5163 0.5
+5211 0.25
+5212 0
+5213 0
+5210 1
+5220 2
+5241 -1.0
+5242 -2.0
+5243 0
+";
+
+    fn parameters(name: &str, units: LinearUnits) -> (WorkOffsets, HomePositions) {
+        let path = ::std::env::temp_dir().join(name);
+        fs::write(&path, VAR_FILE).unwrap();
+        let cnc = Cnc {
+            var_file: Some(path.clone()),
+            linear_units: Some(units),
+            ..Default::default()
+        };
+        let result = get_parameters(&cnc).unwrap();
+        fs::remove_file(path).unwrap();
+        result
+    }
+
+    #[test]
+    fn metric_parameter_file() {
+        let (offsets, homes) = parameters("gcode_timer_mm.var", LinearUnits::Mm);
+        assert_eq!(offsets.active, 1);
+        assert_eq!(offsets.systems[1], (-1., -2., 0.));
+        assert_eq!(offsets.g92, (0.25, 0., 0.));
+        assert_eq!(homes.g28, (1., 2., 0.5));
+    }
+
+    #[test]
+    fn inch_parameter_file_is_converted_to_mm() {
+        let (offsets, homes) = parameters("gcode_timer_inch.var", LinearUnits::Inch);
+        assert_eq!(offsets.systems[1], (-25.4, -50.8, 0.));
+        assert_eq!(offsets.g92_saved, (6.35, 0., 0.));
+        assert_eq!(homes.g28, (25.4, 50.8, 12.7));
+    }
+
+    #[test]
+    fn config_offsets_stay_in_mm() {
+        let cnc = Cnc {
+            linear_units: Some(LinearUnits::Inch),
+            work_offsets: Some(
+                vec![("G55".to_string(), (1., 2., 3.))]
+                    .into_iter()
+                    .collect(),
+            ),
+            g30_position: Some((4., 5., 6.)),
+            ..Default::default()
+        };
+        let (offsets, homes) = get_parameters(&cnc).unwrap();
+        assert_eq!(offsets.systems[1], (1., 2., 3.));
+        assert_eq!(homes.g30, (4., 5., 6.));
+    }
+}
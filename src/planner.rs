@@ -0,0 +1,298 @@
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+
+use super::setup::{Cnc, Firmware};
+use crate::math_tools::*;
+use crate::objects_def::{Block, PathMode};
+
+const DEFAULT_JUNCTION_DEVIATION: f32 = 0.01;
+const DEFAULT_PLANNER_BLOCKS: usize = 64;
+// Grbl's ring buffer holds BLOCK_BUFFER_SIZE - 1 blocks.
+const GRBL_PLANNER_BLOCKS: usize = 15;
+// Share of the acceleration LinuxCNC's TP allows normal to the path in blends.
+const LINUXCNC_BLEND_ACC_RATIO_NORMAL: f32 = 0.866;
+
+struct PlannedBlock {
+    block: Block,
+    max_entry: f32,
+    entry: f32,
+}
+
+pub struct Planner {
+    buffer: VecDeque<PlannedBlock>,
+    size: usize,
+    firmware: Firmware,
+    junction_deviation: f32,
+    junction_accel: Option<(f32, f32, f32)>,
+    entry_speed: f32,
+}
+
+impl Planner {
+    pub fn new(config: &Cnc) -> Planner {
+        let (default_size, junction_accel) = match config.get_firmware() {
+            Firmware::Grbl => (
+                GRBL_PLANNER_BLOCKS,
+                match (config.accel_x, config.accel_y, config.accel_z) {
+                    (Some(x), Some(y), Some(z)) => Some((x, y, z)),
+                    _ => None,
+                },
+            ),
+            _ => (DEFAULT_PLANNER_BLOCKS, None),
+        };
+        Planner {
+            buffer: VecDeque::new(),
+            size: config.planner_blocks.unwrap_or(default_size).max(1),
+            firmware: config.get_firmware(),
+            junction_deviation: config
+                .junction_deviation
+                .unwrap_or(DEFAULT_JUNCTION_DEVIATION),
+            junction_accel,
+            entry_speed: 0.,
+        }
+    }
+
+    // Buffers a block and returns the ones leaving the look-ahead window,
+    // timed with their final entry and exit speeds.
+    pub fn push(&mut self, block: Block) -> Vec<Block> {
+        if block.distance == 0. {
+            return Vec::new();
+        }
+
+        let max_entry = match self.buffer.back() {
+            Some(prev) => self.junction_speed(&prev.block, &block),
+            None => 0.,
+        };
+        self.buffer.push_back(PlannedBlock {
+            block,
+            max_entry,
+            entry: 0.,
+        });
+
+        let mut out = Vec::new();
+        while self.buffer.len() > self.size {
+            self.plan();
+            out.push(self.pop());
+        }
+        out
+    }
+
+    // Empties the buffer, the machine coming to a stop after the last block.
+    pub fn flush(&mut self) -> Vec<Block> {
+        let mut out = Vec::new();
+        while !self.buffer.is_empty() {
+            self.plan();
+            out.push(self.pop());
+        }
+        self.entry_speed = 0.;
+        out
+    }
+
+    fn junction_speed(&self, prev: &Block, next: &Block) -> f32 {
+        let max_speed = prev.speed.min(next.speed);
+        let mut accel = prev.accel.min(next.accel);
+        if accel.is_infinite() {
+            return max_speed;
+        }
+
+        let (px, py, pz) = prev.exit_dir;
+        let (nx, ny, nz) = next.entry_dir;
+        let cos_theta = -(px * nx + py * ny + pz * nz);
+
+        if let Some(limits) = self.junction_accel {
+            // Grbl limits the acceleration along the junction unit vector
+            let (jx, jy, jz) = (nx - px, ny - py, nz - pz);
+            if jx != 0. || jy != 0. || jz != 0. {
+                accel = path_limit((jx, jy, jz), limits);
+            }
+        }
+
+        let straight = cos_theta < -0.999999;
+
+        if self.firmware != Firmware::Grbl {
+            match prev.path_mode {
+                PathMode::ExactStop => return 0.,
+                // G61 only keeps moving through collinear junctions
+                PathMode::ExactPath => return if straight { max_speed } else { 0. },
+                _ => {}
+            }
+        }
+        if prev.exit_dir == (0., 0., 0.) || next.entry_dir == (0., 0., 0.) {
+            // Moves of the extra axes alone have no XYZ direction to turn from
+            return max_speed;
+        }
+        if cos_theta > 0.999999 {
+            // Full reversal
+            return 0.;
+        }
+        if straight {
+            return max_speed;
+        }
+
+        if self.firmware == Firmware::LinuxCnc {
+            return self.blend_speed(prev, next, cos_theta, accel, max_speed);
+        }
+
+        let sin_theta_d2 = (0.5 * (1. - cos_theta)).sqrt();
+        let v2 = accel * self.junction_deviation * sin_theta_d2 / (1. - sin_theta_d2);
+        v2.sqrt().min(max_speed)
+    }
+
+    // LinuxCNC replaces the corner with a circular blend whose radius is
+    // bounded by the G64 P tolerance and by half of the shorter segment.
+    fn blend_speed(
+        &self,
+        prev: &Block,
+        next: &Block,
+        cos_theta: f32,
+        accel: f32,
+        max_speed: f32,
+    ) -> f32 {
+        let (tolerance, naive_tolerance) = match prev.path_mode {
+            PathMode::Blending(p, q) => (p, q),
+            _ => (None, None),
+        };
+        let deflection = (-cos_theta).acos();
+        let half_angle = (PI - deflection) / 2.;
+        let shorter = prev.distance.min(next.distance);
+
+        if let Some(q) = naive_tolerance {
+            // Nearly collinear segments are merged by the naive CAM detector
+            if q > 0. && deflection < PI / 2. && shorter * deflection.sin() <= q {
+                return max_speed;
+            }
+        }
+
+        let mut radius = shorter / 2. * half_angle.tan();
+        if let Some(p) = tolerance {
+            if p > 0. {
+                let sin_half = half_angle.sin();
+                radius = radius.min(p * sin_half / (1. - sin_half));
+            }
+        }
+
+        (LINUXCNC_BLEND_ACC_RATIO_NORMAL * accel * radius)
+            .sqrt()
+            .min(max_speed)
+    }
+
+    // Backward pass from a full stop at the end of the buffer, then forward
+    // pass from the committed entry speed of the first block.
+    fn plan(&mut self) {
+        let mut exit = 0.;
+        for pb in self.buffer.iter_mut().rev() {
+            let reachable = (exit * exit + 2. * pb.block.accel * pb.block.distance).sqrt();
+            pb.entry = pb.max_entry.min(reachable);
+            exit = pb.entry;
+        }
+
+        let mut entry = self.entry_speed;
+        for pb in self.buffer.iter_mut() {
+            pb.entry = pb.entry.min(entry);
+            entry = (pb.entry * pb.entry + 2. * pb.block.accel * pb.block.distance).sqrt();
+        }
+    }
+
+    fn pop(&mut self) -> Block {
+        let pb = self.buffer.pop_front().unwrap();
+        let exit = match self.buffer.front() {
+            Some(next) => next.entry,
+            None => 0.,
+        };
+        let mut block = pb.block;
+        block.duration =
+            trapezoid_duration(block.distance, pb.entry, block.speed, exit, block.accel);
+        self.entry_speed = exit;
+        block
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(distance: f32, dir: (f32, f32, f32)) -> Block {
+        Block {
+            tool_number: None,
+            distance,
+            duration: 0.,
+            speed: 10.,
+            accel: 100.,
+            entry_dir: dir,
+            exit_dir: dir,
+            path_mode: PathMode::Blending(None, None),
+        }
+    }
+
+    fn planner() -> Planner {
+        Planner::new(&Cnc {
+            junction_deviation: Some(0.01),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn straight_junction_keeps_full_speed() {
+        let p = planner();
+        let v = p.junction_speed(&block(10., (1., 0., 0.)), &block(10., (1., 0., 0.)));
+        assert_eq!(v, 10.);
+    }
+
+    #[test]
+    fn right_angle_junction() {
+        let p = planner();
+        let v = p.junction_speed(&block(10., (1., 0., 0.)), &block(10., (0., 1., 0.)));
+        // sin(θ/2) = √½: v² = a·δ·√½ / (1 - √½)
+        let s = 0.5f32.sqrt();
+        let expected = (100. * 0.01 * s / (1. - s)).sqrt();
+        assert!((v - expected).abs() < 1e-4, "{} != {}", v, expected);
+    }
+
+    #[test]
+    fn reversal_stops() {
+        let p = planner();
+        let v = p.junction_speed(&block(10., (1., 0., 0.)), &block(10., (-1., 0., 0.)));
+        assert_eq!(v, 0.);
+    }
+
+    #[test]
+    fn extra_axes_only_junction_is_not_a_corner() {
+        let p = planner();
+        let rotary = block(10., (0., 0., 0.));
+        assert_eq!(p.junction_speed(&block(10., (1., 0., 0.)), &rotary), 10.);
+        assert_eq!(p.junction_speed(&rotary, &block(10., (0., 1., 0.))), 10.);
+    }
+
+    #[test]
+    fn exact_stop_mode_stops() {
+        let p = planner();
+        let mut prev = block(10., (1., 0., 0.));
+        prev.path_mode = PathMode::ExactStop;
+        assert_eq!(p.junction_speed(&prev, &block(10., (1., 0., 0.))), 0.);
+    }
+
+    #[test]
+    fn backward_pass_clamps_to_exit_speed() {
+        // The last block stops within 0.005 at 100/s², so it cannot be
+        // entered faster than √(2·100·0.005) = 1
+        let mut p = planner();
+        p.push(block(10., (1., 0., 0.)));
+        p.push(block(0.005, (1., 0., 0.)));
+        p.plan();
+        let entries: Vec<f32> = p.buffer.iter().map(|pb| pb.entry).collect();
+        assert_eq!(entries[0], 0.);
+        assert!((entries[1] - 1.).abs() < 1e-4, "{}", entries[1]);
+    }
+
+    #[test]
+    fn flushed_blocks_are_timed() {
+        let mut p = planner();
+        p.push(block(10., (1., 0., 0.)));
+        p.push(block(10., (1., 0., 0.)));
+        let blocks = p.flush();
+        // 0.5 to reach 10 at 100/s² in 0.1s, cruise through the junction,
+        // then 0.5 to stop in 0.1s
+        let total: f32 = blocks.iter().map(|b| b.duration).sum();
+        assert!((total - (0.1 + 19. / 10. + 0.1)).abs() < 1e-4, "{}", total);
+        assert_eq!(p.buffer.len(), 0);
+    }
+}
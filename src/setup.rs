@@ -1,32 +1,340 @@
 use super::errors::*;
+use super::grbl_settings::parse_grbl_settings;
+use super::machine_ini::parse_machine_ini;
+use super::output::get_machine_list;
+use super::parameters::get_parameters;
+use super::tool_table::{merge_tool_library, parse_tool_table, ToolEntry, ToolSpec};
+use crate::objects_def::{Axis, HomePositions, WorkOffsets};
 use clap::{App, Arg};
 use home::home_dir;
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::str;
 use toml;
 
-#[derive(Debug, Deserialize)]
+// M codes with a built-in meaning, which cannot be given a custom delay
+const BUILTIN_MCODES: [u8; 7] = [0, 1, 3, 4, 5, 6, 30];
+
+#[derive(Debug)]
 pub struct Config {
     pub cnc: Cnc,
+    pub mcodes: BTreeMap<u8, MCode>,
+    pub offsets: WorkOffsets,
+    pub homes: HomePositions,
+    pub tools: BTreeMap<u8, ToolEntry>,
 }
 
 #[derive(Debug, Deserialize)]
+struct ConfigFile {
+    cnc: Option<Cnc>,
+    machines: Option<BTreeMap<String, Cnc>>,
+    default_machine: Option<String>,
+    mcodes: Option<BTreeMap<String, MCode>>,
+    tools: Option<BTreeMap<String, ToolSpec>>,
+}
+impl ConfigFile {
+    fn select_machine(self, name: Option<String>) -> Result<Cnc> {
+        let mut machines = self.machines.unwrap_or_default();
+        match name.or(self.default_machine) {
+            Some(n) => match machines.remove(&n) {
+                Some(cnc) => Ok(cnc),
+                None => bail!(format!("Unknown machine: {}", n)),
+            },
+            None => match self.cnc {
+                Some(cnc) => Ok(cnc),
+                None if machines.len() == 1 => Ok(machines.into_iter().next().unwrap().1),
+                None => bail!("No machine selected: use --machine or set default_machine"),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
 pub struct Cnc {
     pub speed_x: f32,
     pub speed_y: f32,
     pub speed_z: f32,
+    pub accel_x: Option<f32>,
+    pub accel_y: Option<f32>,
+    pub accel_z: Option<f32>,
+    pub junction_deviation: Option<f32>,
+    pub planner_blocks: Option<usize>,
+    pub firmware: Option<Firmware>,
+    pub arc_tolerance: Option<f32>,
+    pub travel_x: Option<f32>,
+    pub travel_y: Option<f32>,
+    pub travel_z: Option<f32>,
+    pub axes: Option<BTreeMap<String, AxisSettings>>,
+    pub tool_table: Option<PathBuf>,
+    pub dwell_unit: Option<DwellUnit>,
+    pub pause_time: Option<f32>,
+    pub tool_changer: Option<ToolChanger>,
+    pub atc_change_time: Option<f32>,
+    pub manual_change_time: Option<f32>,
+    pub tool_probe_time: Option<f32>,
+    pub spindle_up_time: Option<f32>,
+    pub spindle_down_time: Option<f32>,
+    pub spindle_max_rpm: Option<f32>,
+    pub var_file: Option<PathBuf>,
+    pub linear_units: Option<LinearUnits>,
+    pub work_offsets: Option<BTreeMap<String, (f32, f32, f32)>>,
+    pub g28_position: Option<(f32, f32, f32)>,
+    pub g30_position: Option<(f32, f32, f32)>,
 }
+impl Cnc {
+    pub fn get_firmware(&self) -> Firmware {
+        self.firmware.unwrap_or(Firmware::Generic)
+    }
+
+    // Marlin reads G4 P in milliseconds, the other dialects in seconds
+    pub fn get_dwell_unit(&self) -> DwellUnit {
+        match (self.dwell_unit, self.get_firmware()) {
+            (Some(u), _) => u,
+            (None, Firmware::Marlin) => DwellUnit::Milliseconds,
+            (None, _) => DwellUnit::Seconds,
+        }
+    }
+
+    // Factor from the units of the machine files (parameter file, tool
+    // table) to mm
+    pub fn get_linear_scale(&self) -> f32 {
+        match self.linear_units.unwrap_or(LinearUnits::Mm) {
+            LinearUnits::Mm => 1.,
+            LinearUnits::Inch => 25.4,
+        }
+    }
 
-pub fn get_config() -> Result<(PathBuf, Config, bool, (u8, Option<PathBuf>))> {
-    let (input, config_path, list_result, logconf) =
-        get_args().chain_err(|| "Error parsing arguments")?;
-    let config = parse_config(config_path).chain_err(|| "Error parsing config file")?;
-    Ok((input, config, list_result, logconf))
+    // Duration of a M6, probing the new tool's length included
+    pub fn get_tool_change_time(&self) -> f32 {
+        let change = match self.tool_changer.unwrap_or(ToolChanger::Atc) {
+            ToolChanger::Atc => self.atc_change_time,
+            ToolChanger::Manual => self.manual_change_time,
+        };
+        change.unwrap_or(0.) + self.tool_probe_time.unwrap_or(0.)
+    }
+
+    fn check(&self) -> Result<()> {
+        match (self.accel_x, self.accel_y, self.accel_z) {
+            (Some(_), Some(_), Some(_)) | (None, None, None) => {}
+            _ => bail!("accel_x, accel_y and accel_z must be set together"),
+        }
+        if self.get_firmware() == Firmware::Grbl && self.accel_x.is_none() {
+            bail!("Grbl firmware profile requires accel_x, accel_y and accel_z");
+        }
+        let limits = [
+            ("speed_x", Some(self.speed_x)),
+            ("speed_y", Some(self.speed_y)),
+            ("speed_z", Some(self.speed_z)),
+            ("accel_x", self.accel_x),
+            ("accel_y", self.accel_y),
+            ("accel_z", self.accel_z),
+        ];
+        for &(name, value) in limits.iter() {
+            if value.map_or(false, |v| v <= 0.) {
+                bail!(format!("{} must be positive", name));
+            }
+        }
+        let times = [
+            ("pause_time", self.pause_time),
+            ("atc_change_time", self.atc_change_time),
+            ("manual_change_time", self.manual_change_time),
+            ("tool_probe_time", self.tool_probe_time),
+            ("spindle_up_time", self.spindle_up_time),
+            ("spindle_down_time", self.spindle_down_time),
+        ];
+        for &(name, time) in times.iter() {
+            if let Some(t) = time {
+                if t < 0. {
+                    bail!(format!("{} must not be negative", name));
+                }
+            }
+        }
+        if let Some(rpm) = self.spindle_max_rpm {
+            if rpm <= 0. {
+                bail!("spindle_max_rpm must be positive");
+            }
+        }
+        for (key, settings) in self.axes.iter().flat_map(|m| m.iter()) {
+            settings
+                .check(key)
+                .chain_err(|| format!("Invalid [cnc.axes.{}] settings", key))?;
+        }
+        Ok(())
+    }
 }
 
-fn parse_config(path: PathBuf) -> Result<Config> {
+// Axis besides X, Y and Z, in the [axes] table keyed by its letter: A, B
+// and C are rotary, U, V and W linear.
+#[derive(Debug, Deserialize)]
+pub struct AxisSettings {
+    pub speed: f32,
+    pub accel: Option<f32>,
+    pub radius: Option<f32>,
+    pub rollover: Option<bool>,
+}
+impl AxisSettings {
+    fn check(&self, key: &str) -> Result<()> {
+        let mut letters = key.chars();
+        let axis = match (letters.next(), letters.next()) {
+            (Some(l), None) => Axis::from_letter(l.to_ascii_uppercase()),
+            _ => None,
+        };
+        let axis = match axis {
+            Some(a) if a.extra_index().is_some() => a,
+            Some(_) => bail!("X, Y and Z limits are set by speed_x, accel_x..."),
+            None => bail!(format!("Unknown axis: {}", key)),
+        };
+        if self.speed <= 0. {
+            bail!("speed must be positive");
+        }
+        for &(name, value) in [("accel", self.accel), ("radius", self.radius)].iter() {
+            if value.map_or(false, |v| v <= 0.) {
+                bail!(format!("{} must be positive", name));
+            }
+        }
+        if !axis.is_rotary() && (self.radius.is_some() || self.rollover.is_some()) {
+            bail!("radius and rollover only apply to rotary axes");
+        }
+        Ok(())
+    }
+}
+
+// Auxiliary M code (coolant, vacuum, user code) and the time it takes
+#[derive(Debug, Deserialize)]
+pub struct MCode {
+    pub delay: f32,
+    pub label: Option<String>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Firmware {
+    Generic,
+    Grbl,
+    LinuxCnc,
+    Marlin,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum DwellUnit {
+    Seconds,
+    Milliseconds,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum LinearUnits {
+    Mm,
+    Inch,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolChanger {
+    Atc,
+    Manual,
+}
+
+struct Args {
+    input: Option<PathBuf>,
+    config_path: Option<PathBuf>,
+    machine_ini: Option<PathBuf>,
+    grbl_settings: Option<PathBuf>,
+    machine: Option<String>,
+    list_machines: bool,
+    list_result: bool,
+    block_delete: bool,
+    logconf: (u8, Option<PathBuf>),
+}
+
+pub fn get_config() -> Result<(PathBuf, Config, bool, bool, (u8, Option<PathBuf>))> {
+    let args = get_args().chain_err(|| "Error parsing arguments")?;
+
+    let imported = match (args.machine_ini, args.grbl_settings) {
+        (Some(ini), _) => {
+            let cnc = parse_machine_ini(&ini).chain_err(|| "Error importing machine INI file")?;
+            cnc.check().chain_err(|| "Invalid machine INI file")?;
+            Some(cnc)
+        }
+        (None, Some(grbl)) => {
+            let cnc =
+                parse_grbl_settings(&grbl).chain_err(|| "Error importing Grbl settings file")?;
+            cnc.check().chain_err(|| "Invalid Grbl settings file")?;
+            Some(cnc)
+        }
+        (None, None) => None,
+    };
+
+    let mut file = match args.config_path {
+        Some(p) => Some(parse_config(p).chain_err(|| "Error parsing config file")?),
+        None => None,
+    };
+
+    if args.list_machines {
+        let (names, default) = match file {
+            Some(ref f) => (
+                f.machines
+                    .as_ref()
+                    .map(|m| m.keys().cloned().collect())
+                    .unwrap_or_default(),
+                f.default_machine.clone(),
+            ),
+            None => (Vec::new(), None),
+        };
+        println!("{}", get_machine_list(names, default));
+        ::std::process::exit(0);
+    }
+
+    let mcodes = match file {
+        Some(ref mut f) => parse_mcodes(f.mcodes.take().unwrap_or_default())
+            .chain_err(|| "Invalid [mcodes] table")?,
+        None => BTreeMap::new(),
+    };
+    let library = match file {
+        Some(ref mut f) => f.tools.take().unwrap_or_default(),
+        None => BTreeMap::new(),
+    };
+
+    let cnc = match (file, imported) {
+        (_, Some(cnc)) => cnc,
+        (Some(f), None) => f
+            .select_machine(args.machine)
+            .chain_err(|| "Error selecting machine profile")?,
+        (None, None) => bail!("No config file found"),
+    };
+    let (offsets, homes) = get_parameters(&cnc).chain_err(|| "Error loading parameters")?;
+    let tools = match cnc.tool_table {
+        Some(ref path) => parse_tool_table(path, cnc.get_linear_scale())
+            .chain_err(|| format!("Error loading tool table {}", path.display()))?,
+        None => BTreeMap::new(),
+    };
+    let tools = merge_tool_library(tools, library).chain_err(|| "Invalid [tools] table")?;
+    let config = Config {
+        cnc,
+        mcodes,
+        offsets,
+        homes,
+        tools,
+    };
+
+    let input = match args.input {
+        Some(i) => i,
+        None => bail!("No input file specified"),
+    };
+
+    Ok((
+        input,
+        config,
+        args.list_result,
+        args.block_delete,
+        args.logconf,
+    ))
+}
+
+fn parse_config(path: PathBuf) -> Result<ConfigFile> {
     let mut file = File::open(path).chain_err(|| "Error opening config file")?;
     let mut buffer = Vec::new();
 
@@ -34,13 +342,47 @@ fn parse_config(path: PathBuf) -> Result<Config> {
         .chain_err(|| "Error reading config file")?;
 
     let filestr = str::from_utf8(&buffer).chain_err(|| "Error converting buffer to utf8 str")?;
-    let config: Config =
+    let config: ConfigFile =
         toml::from_str(filestr).chain_err(|| "Error parsing Config from config file")?;
 
+    if let Some(ref cnc) = config.cnc {
+        cnc.check().chain_err(|| "Invalid [cnc] table")?;
+    }
+    if let Some(ref machines) = config.machines {
+        for (name, cnc) in machines.iter() {
+            cnc.check()
+                .chain_err(|| format!("Invalid [machines.{}] table", name))?;
+        }
+    }
+
     Ok(config)
 }
 
-fn get_args() -> Result<(PathBuf, PathBuf, bool, (u8, Option<PathBuf>))> {
+// Keys are M code numbers, with or without the leading M
+fn parse_mcodes(table: BTreeMap<String, MCode>) -> Result<BTreeMap<u8, MCode>> {
+    let mut mcodes = BTreeMap::new();
+    for (key, mcode) in table {
+        let number: u8 = key
+            .trim_start_matches(|c| c == 'M' || c == 'm')
+            .parse()
+            .chain_err(|| format!("Invalid M code: {}", key))?;
+        if BUILTIN_MCODES.contains(&number) {
+            bail!(format!(
+                "M{} has a built-in meaning and cannot be set",
+                number
+            ));
+        }
+        if mcode.delay < 0. {
+            bail!(format!("Negative delay for M{}", number));
+        }
+        if mcodes.insert(number, mcode).is_some() {
+            bail!(format!("M{} defined twice", number));
+        }
+    }
+    Ok(mcodes)
+}
+
+fn get_args() -> Result<Args> {
     let matches = App::new("Gcode_timer")
         .version("0.1.0")
         .author("Thibault M. <tmarion90@gmail.com>")
@@ -48,7 +390,7 @@ fn get_args() -> Result<(PathBuf, PathBuf, bool, (u8, Option<PathBuf>))> {
         .arg(
             Arg::with_name("INPUT")
                 .help("Sets the input file to use")
-                .required(true)
+                .required_unless("list-machines")
                 .index(1),
         )
         .arg(
@@ -59,12 +401,47 @@ fn get_args() -> Result<(PathBuf, PathBuf, bool, (u8, Option<PathBuf>))> {
                 .help("Sets a custom config file")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("machine-ini")
+                .long("machine-ini")
+                .value_name("FILE")
+                .help("Imports machine limits from a LinuxCNC INI file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("machine")
+                .short("m")
+                .long("machine")
+                .value_name("NAME")
+                .conflicts_with_all(&["machine-ini", "grbl-settings"])
+                .help("Selects a [machines.<NAME>] profile from the config file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("list-machines")
+                .long("list-machines")
+                .help("Lists the machine profiles of the config file and exits"),
+        )
+        .arg(
+            Arg::with_name("grbl-settings")
+                .long("grbl-settings")
+                .value_name("FILE")
+                .conflicts_with("machine-ini")
+                .help("Imports machine limits from a Grbl `$$` settings dump")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("list")
                 .short("l")
                 .long("list")
                 .help("Displays results tool by tool"),
         )
+        .arg(
+            Arg::with_name("block-delete")
+                .short("b")
+                .long("block-delete")
+                .help("Skips lines starting with '/' (block delete switch on)"),
+        )
         .arg(
             Arg::with_name("v")
                 .short("v")
@@ -94,10 +471,12 @@ fn get_args() -> Result<(PathBuf, PathBuf, bool, (u8, Option<PathBuf>))> {
         .get_matches();
 
     let input = match matches.value_of("INPUT") {
-        Some(s) => Path::new(s)
-            .canonicalize()
-            .chain_err(|| "Error canonicalizing path")?,
-        None => bail!("No input file specified"),
+        Some(s) => Some(
+            Path::new(s)
+                .canonicalize()
+                .chain_err(|| "Error canonicalizing path")?,
+        ),
+        None => None,
     };
 
     let mut verbose = match matches.occurrences_of("v") as u8 {
@@ -108,18 +487,47 @@ fn get_args() -> Result<(PathBuf, PathBuf, bool, (u8, Option<PathBuf>))> {
         verbose = 0;
     }
 
+    let machine_ini = match matches.value_of("machine-ini") {
+        Some(s) => Some(
+            Path::new(s)
+                .canonicalize()
+                .chain_err(|| "Error canonicalizing path")?,
+        ),
+        None => None,
+    };
+
+    let grbl_settings = match matches.value_of("grbl-settings") {
+        Some(s) => Some(
+            Path::new(s)
+                .canonicalize()
+                .chain_err(|| "Error canonicalizing path")?,
+        ),
+        None => None,
+    };
+
     let config_path = match matches.value_of("config") {
-        Some(s) => Path::new(s)
-            .canonicalize()
-            .chain_err(|| "Error canonicalizing path")?,
+        Some(s) => Some(
+            Path::new(s)
+                .canonicalize()
+                .chain_err(|| "Error canonicalizing path")?,
+        ),
         None => {
             let home = match home_dir() {
                 Some(h) => h,
                 None => bail!("Could not get home directory"),
             };
-            home.join(Path::new(".gcode_timer/config.toml"))
-                .canonicalize()
-                .chain_err(|| "Error canonicalizing path")?
+            let default_path = home.join(Path::new(".gcode_timer/config.toml"));
+            // The default config file is optional when machine limits are imported
+            let imported = machine_ini.is_some() || grbl_settings.is_some();
+            if imported && !default_path.exists() {
+                None
+            } else {
+                Some(
+                    default_path
+                        .canonicalize()
+                        .chain_err(|| "Error canonicalizing path")?,
+                )
+            }
         }
     };
 
@@ -128,10 +536,15 @@ fn get_args() -> Result<(PathBuf, PathBuf, bool, (u8, Option<PathBuf>))> {
         None => None,
     };
 
-    Ok((
+    Ok(Args {
         input,
         config_path,
-        matches.is_present("list"),
-        (verbose, logfile),
-    ))
+        machine_ini,
+        grbl_settings,
+        machine: matches.value_of("machine").map(String::from),
+        list_machines: matches.is_present("list-machines"),
+        list_result: matches.is_present("list"),
+        block_delete: matches.is_present("block-delete"),
+        logconf: (verbose, logfile),
+    })
 }
@@ -0,0 +1,205 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use super::errors::*;
+
+// Tool geometry in mm, and cutting limits in mm/min and RPM
+#[derive(Debug, Clone, Default)]
+pub struct ToolEntry {
+    pub diameter: f32,
+    pub length: f32,
+    pub flutes: Option<u32>,
+    pub description: Option<String>,
+    pub max_feed: Option<f32>,
+    pub max_rpm: Option<f32>,
+}
+
+// Tool of the [tools] table of the config file. Values given override the
+// ones of the tool table.
+#[derive(Debug, Deserialize)]
+pub struct ToolSpec {
+    diameter: Option<f32>,
+    length: Option<f32>,
+    flutes: Option<u32>,
+    description: Option<String>,
+    max_feed: Option<f32>,
+    max_rpm: Option<f32>,
+}
+
+// Parses a tool number given as a float word (T, D, H)
+pub fn tool_index(value: f32) -> Result<u8> {
+    if !(0. ..=255.).contains(&value) || value.fract() != 0. {
+        bail!(format!("Invalid tool number: {}", value));
+    }
+    Ok(value as u8)
+}
+
+// Reads a LinuxCNC tool table, whose lines look like
+// `T1 P1 Z+12.5 D6.0 ;comment`. Lengths are in machine units, scale
+// converting them to mm.
+pub fn parse_tool_table(path: &Path, scale: f32) -> Result<BTreeMap<u8, ToolEntry>> {
+    let mut file = File::open(path).chain_err(|| "Error opening tool table")?;
+    let mut text = String::new();
+    file.read_to_string(&mut text)
+        .chain_err(|| "Error reading tool table")?;
+    tools_from_table(&text, scale)
+}
+
+fn tools_from_table(text: &str, scale: f32) -> Result<BTreeMap<u8, ToolEntry>> {
+    let mut tools = BTreeMap::new();
+    for (n, line) in text.lines().enumerate() {
+        let (line, comment) = match line.find(';') {
+            Some(i) => (&line[..i], Some(line[i + 1..].trim())),
+            None => (line, None),
+        };
+        let mut number = None;
+        let mut entry = ToolEntry {
+            description: comment.filter(|c| !c.is_empty()).map(String::from),
+            ..Default::default()
+        };
+        for token in line.split_whitespace() {
+            let letter = token.chars().next().unwrap_or(' ').to_ascii_uppercase();
+            let value: f32 = token[letter.len_utf8()..]
+                .parse()
+                .chain_err(|| format!("Invalid value {} at line {}", token, n + 1))?;
+            match letter {
+                'T' => number = Some(value),
+                'D' => entry.diameter = value * scale,
+                'Z' => entry.length = value * scale,
+                _ => {}
+            }
+        }
+        let number = match number {
+            Some(t) => tool_index(t).chain_err(|| format!("Error at line {}", n + 1))?,
+            None if line.trim().is_empty() => continue,
+            None => bail!(format!("No tool number at line {}", n + 1)),
+        };
+        if tools.insert(number, entry).is_some() {
+            bail!(format!("Tool {} defined twice", number));
+        }
+    }
+    Ok(tools)
+}
+
+// Adds the tools of the [tools] table, keyed "T3" or "3", to the tool table.
+// The [tools] table is in mm.
+pub fn merge_tool_library(
+    mut tools: BTreeMap<u8, ToolEntry>,
+    library: BTreeMap<String, ToolSpec>,
+) -> Result<BTreeMap<u8, ToolEntry>> {
+    for (key, spec) in library {
+        let number: u8 = key
+            .trim_start_matches(['T', 't'])
+            .parse()
+            .chain_err(|| format!("Invalid tool number: {}", key))?;
+        for &(name, value) in [
+            ("diameter", spec.diameter),
+            ("max_feed", spec.max_feed),
+            ("max_rpm", spec.max_rpm),
+        ]
+        .iter()
+        {
+            if value.is_some_and(|v| v < 0.) {
+                bail!(format!("Negative {} for tool {}", name, number));
+            }
+        }
+
+        let entry = tools.entry(number).or_default();
+        if let Some(d) = spec.diameter {
+            entry.diameter = d;
+        }
+        if let Some(l) = spec.length {
+            entry.length = l;
+        }
+        if spec.flutes.is_some() {
+            entry.flutes = spec.flutes;
+        }
+        if spec.description.is_some() {
+            entry.description = spec.description;
+        }
+        if spec.max_feed.is_some() {
+            entry.max_feed = spec.max_feed;
+        }
+        if spec.max_rpm.is_some() {
+            entry.max_rpm = spec.max_rpm;
+        }
+    }
+    Ok(tools)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOOL_TABLE: &str = "T1 P1 Z+50 D6 ;6mm endmill
+t2 p2 z-1.5 d0.25
+; spare pocket
+
+T3 P3 Z0 D3 ;
+";
+
+    fn spec(diameter: Option<f32>, description: Option<&str>) -> ToolSpec {
+        ToolSpec {
+            diameter,
+            length: None,
+            flutes: Some(2),
+            description: description.map(String::from),
+            max_feed: None,
+            max_rpm: None,
+        }
+    }
+
+    #[test]
+    fn metric_tool_table() {
+        let tools = tools_from_table(TOOL_TABLE, 1.).unwrap();
+        assert_eq!(tools.len(), 3);
+        assert_eq!((tools[&1].diameter, tools[&1].length), (6., 50.));
+        assert_eq!(tools[&1].description, Some("6mm endmill".to_string()));
+        assert_eq!(tools[&2].length, -1.5);
+        assert_eq!(tools[&3].description, None);
+    }
+
+    #[test]
+    fn inch_tool_table_is_converted_to_mm() {
+        let tools = tools_from_table(TOOL_TABLE, 25.4).unwrap();
+        assert_eq!((tools[&2].diameter, tools[&2].length), (6.35, -38.1));
+    }
+
+    #[test]
+    fn malformed_tool_tables() {
+        assert!(tools_from_table("T1 D3\nT1 D4\n", 1.).is_err());
+        assert!(tools_from_table("P1 D3\n", 1.).is_err());
+        assert!(tools_from_table("T1.5 D3\n", 1.).is_err());
+        assert!(tools_from_table("T1 Dx\n", 1.).is_err());
+    }
+
+    #[test]
+    fn library_overrides_the_tool_table() {
+        let tools = tools_from_table(TOOL_TABLE, 1.).unwrap();
+        let library = vec![
+            ("T1".to_string(), spec(None, Some("roughing"))),
+            ("4".to_string(), spec(Some(10.), None)),
+        ]
+        .into_iter()
+        .collect();
+        let tools = merge_tool_library(tools, library).unwrap();
+        assert_eq!(tools[&1].diameter, 6.);
+        assert_eq!(tools[&1].flutes, Some(2));
+        assert_eq!(tools[&1].description, Some("roughing".to_string()));
+        assert_eq!((tools[&4].diameter, tools[&4].length), (10., 0.));
+    }
+
+    #[test]
+    fn invalid_library_entries() {
+        let negative = vec![("T1".to_string(), spec(Some(-1.), None))]
+            .into_iter()
+            .collect();
+        assert!(merge_tool_library(BTreeMap::new(), negative).is_err());
+        let bad_key = vec![("Tx".to_string(), spec(None, None))]
+            .into_iter()
+            .collect();
+        assert!(merge_tool_library(BTreeMap::new(), bad_key).is_err());
+    }
+}